@@ -1,23 +1,93 @@
 mod services;
 
-use std::io::Write;
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use futures::StreamExt;
-use rspotify::prelude::BaseClient;
-use rspotify_model::{PlayableItem, PlaylistId};
-use services::{spotify, subsonic};
-use submarine::Client;
+use regex::Regex;
+use services::{
+    cache::CacheMode,
+    csv_import::{CsvColumns, CsvPlaylists},
+    destination::{Destination, PlaylistSummary},
+    export::SpotifyExport,
+    marker::SourceMarker,
+    playlist_file::{LibraryRoot, PlaylistFiles, PlaylistFormat},
+    source::{
+        Collaborative, Collection, CollectionId, CollectionMetadata, PlaylistFilter, SourceProvider,
+    },
+    spotify::{self, SpotifySource},
+    subsonic::{LibraryScan, SubsonicDestination},
+    sync::{ImportHistory, ImportedPlaylist, PlaylistDiff, StarredSongs},
+};
 
-use services::{Track, TrackSource, search, subsonic::get_song};
-
-use crate::services::subsonic::add_songs_to_favorites;
+use services::{
+    Candidate, Confidence, Match, SearchResult, Track, TrackSource,
+    config::MatchConfig,
+    library::Library,
+    mappings::{Mapping, MappingStore},
+    search,
+};
 
 #[derive(Parser)]
-#[command(name = "TuneTracker")]
+#[command(name = "TuneTracker", subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(long, help = "Id of the playlist to import")]
-    playlist: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "Whether to import a playlist or the songs saved to your Liked Songs"
+    )]
+    source: ImportSource,
+    #[clap(
+        long,
+        help = "Id of the playlist to import, required when importing a single playlist"
+    )]
+    playlist: Option<String>,
+    #[clap(
+        long,
+        conflicts_with_all = ["playlist", "source"],
+        help = "Import every playlist in your library, narrowed down by the playlist filters"
+    )]
+    all_playlists: bool,
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "With --all-playlists, only import playlists whose name matches"
+    )]
+    include_name: Option<Regex>,
+    #[clap(
+        long,
+        value_name = "REGEX",
+        help = "With --all-playlists, leave out playlists whose name matches"
+    )]
+    exclude_name: Option<Regex>,
+    #[clap(
+        long,
+        value_name = "USER",
+        help = "With --all-playlists, only import playlists owned by this user (ID or display name), can be repeated"
+    )]
+    include_owner: Vec<String>,
+    #[clap(
+        long,
+        value_name = "USER",
+        help = "With --all-playlists, leave out playlists owned by this user (ID or display name), can be repeated"
+    )]
+    exclude_owner: Vec<String>,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "With --all-playlists, whether collaborative playlists are imported"
+    )]
+    collaborative: Collaborative,
     #[clap(
         long,
         default_value_t,
@@ -25,16 +95,182 @@ struct Args {
         help = "Whether to add songs to a new playlist or add them to favorited songs"
     )]
     destination: TrackDestination,
-    #[clap(long, help = "Spotify client id")]
-    client_id: String,
-    #[clap(long, help = "Spotify client secret")]
-    client_secret: String,
-    #[clap(long, help = "URL of the subsonic server")]
-    subsonic_url: String,
-    #[clap(long, help = "Username of the user on the subsonic server")]
-    subsonic_user: String,
-    #[clap(long, help = "Password for the user account")]
-    subsonic_password: String,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Write playlists as files to this directory instead of creating them on the subsonic server, songs are still matched against the subsonic library"
+    )]
+    playlist_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "With --playlist-dir, the format playlist files are written in"
+    )]
+    playlist_format: PlaylistFormat,
+    #[clap(
+        long,
+        value_name = "[SERVER=]LOCAL",
+        help = "With --playlist-dir, where the library is found locally. Replaces SERVER at the start of the paths the server reports, or is put in front of them when SERVER is left out"
+    )]
+    library_root: Option<String>,
+    #[clap(
+        long,
+        help = "With --playlist-dir, also create the playlists on the subsonic server"
+    )]
+    keep_server_playlist: bool,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "What to do with a playlist that was already imported, found by the import history or the marker in its comment"
+    )]
+    on_existing: ExistingPolicy,
+    #[clap(
+        long,
+        conflicts_with = "on_existing",
+        help = "Same as --on-existing update. With --destination favorites, also removes the favorites an earlier import added that were removed at the source"
+    )]
+    sync: bool,
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        default_value = "{description}",
+        help = "Comment of imported playlists, with {description}, {name}, {provider}, {playlist} and {date} filled in. A marker recognising the playlist is added after it"
+    )]
+    description_template: String,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Read playlists and Liked Songs from an extracted spotify data export instead of the spotify API, playlists are found by name"
+    )]
+    from_export: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with = "from_export",
+        help = "Read playlists from a CSV file (eg. from Exportify) instead of the spotify API, playlists are found by name"
+    )]
+    from_csv: Option<PathBuf>,
+    #[clap(
+        long,
+        requires = "from_csv",
+        help = "TOML file naming the CSV columns that hold each detail of a track"
+    )]
+    csv_columns: Option<PathBuf>,
+    #[clap(
+        long = "csv-column",
+        value_name = "NAME=HEADER",
+        requires = "from_csv",
+        help = "Override a single CSV column (eg. --csv-column title=Song), can be repeated"
+    )]
+    csv_column_overrides: Vec<String>,
+    #[clap(
+        long,
+        required_unless_present_any = ["from_export", "from_csv"],
+        help = "Spotify client id"
+    )]
+    client_id: Option<String>,
+    #[clap(
+        long,
+        required_unless_present_any = ["from_export", "from_csv"],
+        help = "Spotify client secret"
+    )]
+    client_secret: Option<String>,
+    #[clap(long, required = true, help = "URL of the subsonic server")]
+    subsonic_url: Option<String>,
+    #[clap(
+        long,
+        required = true,
+        help = "Username of the user on the subsonic server"
+    )]
+    subsonic_user: Option<String>,
+    #[clap(long, required = true, help = "Password for the user account")]
+    subsonic_password: Option<String>,
+    #[clap(
+        long,
+        help = "Fetch the whole subsonic library again instead of only what changed since it was cached"
+    )]
+    refresh_cache: bool,
+    #[clap(
+        long,
+        conflicts_with = "refresh_cache",
+        help = "Fetch the whole subsonic library without reading or saving the cache"
+    )]
+    no_cache: bool,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "How the songs of the subsonic library are listed, servers that don't return every song for an empty search (eg. Gonic or Airsonic-Advanced) are listed by album"
+    )]
+    library_scan: LibraryScan,
+    #[clap(
+        long,
+        help = "TOML file with the weights and threshold used for matching tracks"
+    )]
+    match_config: Option<PathBuf>,
+    #[clap(
+        long = "match",
+        value_name = "NAME=VALUE",
+        help = "Override a single matching setting (eg. --match year_weight=0), can be repeated"
+    )]
+    match_overrides: Vec<String>,
+    #[clap(
+        long,
+        default_value_t,
+        value_enum,
+        help = "What to do with tracks that couldn't be matched, prompting falls back to report when stdin isn't a terminal"
+    )]
+    on_missing: MissingPolicy,
+    #[clap(
+        long,
+        global = true,
+        help = "JSON file remembering tracks resolved by hand, defaults to mappings.json in the user's data directory"
+    )]
+    mappings: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the tracks that were resolved by hand in earlier imports
+    Mappings {
+        #[command(subcommand)]
+        action: MappingAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum MappingAction {
+    /// List every remembered track
+    List,
+    /// Always use a subsonic song for a source track
+    Set {
+        /// Source track ID as listed, or a spotify track ID or URI
+        source_id: String,
+        /// Subsonic song ID
+        subsonic_id: String,
+    },
+    /// Always skip a source track
+    Skip {
+        /// Source track ID as listed, or a spotify track ID or URI
+        source_id: String,
+    },
+    /// Forget a source track, it will be matched normally again
+    Remove {
+        /// Source track ID as listed, or a spotify track ID or URI
+        source_id: String,
+    },
+    /// Forget every remembered track
+    Clear,
+}
+
+#[derive(Default, Clone, PartialEq, ValueEnum)]
+enum ImportSource {
+    #[default]
+    Playlist,
+    /// The songs saved to your Liked Songs
+    Liked,
 }
 
 #[derive(Default, Clone, PartialEq, ValueEnum)]
@@ -44,6 +280,64 @@ enum TrackDestination {
     Favorites,
 }
 
+#[derive(Default, Clone, Copy, PartialEq, ValueEnum)]
+enum MissingPolicy {
+    /// Ask the user what to do for every missing track
+    #[default]
+    Prompt,
+    /// Leave missing tracks out
+    Skip,
+    /// Exit without importing anything if any track is missing
+    Fail,
+    /// Leave missing tracks out and list them once the import is done
+    Report,
+    /// Use the best candidate for missing tracks, no matter how low it scored
+    BestGuess,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, ValueEnum)]
+enum ExistingPolicy {
+    /// Leave the playlist as it is and import nothing
+    #[default]
+    Refuse,
+    /// Delete the playlist and import it as a new one
+    Replace,
+    /// Apply the changes since the last import to the playlist, they're listed before they're applied
+    Update,
+}
+
+/// Everything shared between the imports of a run, so importing many playlists only logs in
+/// and fetches the library once
+struct Importer<D> {
+    destination: D,
+    library: Library,
+    match_config: MatchConfig,
+    /// Tracks the user resolves by hand are remembered for the next import
+    mappings: RefCell<MappingStore>,
+    /// What imports created is remembered so it can be synced
+    history: RefCell<ImportHistory>,
+    options: ImportOptions,
+}
+
+/// How the imports of a run are done, as picked on the command line
+struct ImportOptions {
+    on_missing: MissingPolicy,
+    on_existing: ExistingPolicy,
+    target: TrackDestination,
+    /// The comment of created playlists, see `SourceMarker::describe`
+    description_template: String,
+}
+
+/// How the user resolved a missing track
+enum Resolution {
+    Found(Box<Track>),
+    Skip,
+    AlwaysSkip,
+}
+
+/// How many candidates are listed when a track is missing
+const PROMPT_CANDIDATES: usize = 5;
+
 // Colors
 pub const YELLOW: &str = "\x1b[33m";
 pub const GREEN: &str = "\x1b[32m";
@@ -54,174 +348,1013 @@ pub const RESET: &str = "\x1b[0m";
 async fn main() {
     let args = Args::parse();
 
-    let spotify_client = spotify::login_spotify(args.client_id, args.client_secret).await;
+    let mappings_path = args
+        .mappings
+        .clone()
+        .unwrap_or_else(MappingStore::default_path);
+    let mut mappings = MappingStore::load(mappings_path).unwrap_or_else(|e| {
+        println!("Error loading mappings! {e}");
+        std::process::exit(1);
+    });
+
+    if let Some(Command::Mappings { action }) = args.command {
+        if let Err(e) = manage_mappings(&mut mappings, action) {
+            println!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut match_config = match &args.match_config {
+        Some(path) => MatchConfig::load(path).unwrap_or_else(|e| {
+            println!("Error loading match config! {e}");
+            std::process::exit(1);
+        }),
+        None => MatchConfig::default(),
+    };
+
+    for setting in &args.match_overrides {
+        if let Err(e) = match_config.set(setting) {
+            println!("Error in match setting! {e}");
+            std::process::exit(1);
+        }
+    }
+
+    // Checked before logging in so a typo doesn't cost a full library download.
+    // A CSV file holding a single playlist doesn't need it named
+    if args.source == ImportSource::Playlist
+        && !args.all_playlists
+        && args.from_csv.is_none()
+        && args.playlist.is_none()
+    {
+        println!("--playlist is required when importing a playlist");
+        std::process::exit(1);
+    }
+
+    // Offline sources are read before logging in so a wrong path fails right away
+    if let Some(path) = &args.from_export {
+        let export = SpotifyExport::load(path).unwrap_or_else(|e| {
+            println!("Error reading spotify export! {e}");
+            std::process::exit(1);
+        });
+
+        run(export, args, mappings, match_config).await;
+    } else if let Some(path) = &args.from_csv {
+        let mut columns = match &args.csv_columns {
+            Some(columns) => CsvColumns::load(columns).unwrap_or_else(|e| {
+                println!("Error loading CSV columns! {e}");
+                std::process::exit(1);
+            }),
+            None => CsvColumns::default(),
+        };
+
+        for setting in &args.csv_column_overrides {
+            if let Err(e) = columns.set(setting) {
+                println!("Error in CSV column! {e}");
+                std::process::exit(1);
+            }
+        }
+
+        let playlists = CsvPlaylists::load(path, &columns).unwrap_or_else(|e| {
+            println!("Error reading CSV file! {e}");
+            std::process::exit(1);
+        });
+
+        run(playlists, args, mappings, match_config).await;
+    } else {
+        if let Some(playlist) = &args.playlist
+            && let Err(e) = spotify::check_playlist_id(playlist)
+        {
+            println!("{e}");
+            std::process::exit(1);
+        }
+
+        let (Some(client_id), Some(client_secret)) = (&args.client_id, &args.client_secret) else {
+            unreachable!("clap requires spotify credentials without an export or CSV file");
+        };
 
-    let subsonic_client = subsonic::login_subsonic(
+        let client = spotify::login_spotify(client_id.clone(), client_secret.clone()).await;
+        run(SpotifySource::new(client), args, mappings, match_config).await;
+    }
+}
+
+/// Imports the collections picked on the command line from a source into subsonic
+async fn run(
+    source: impl SourceProvider,
+    args: Args,
+    mappings: MappingStore,
+    match_config: MatchConfig,
+) {
+    // Clap requires these whenever no subcommand is given
+    let (Some(subsonic_url), Some(subsonic_user), Some(subsonic_password)) = (
         args.subsonic_url,
         args.subsonic_user,
         args.subsonic_password,
-    );
+    ) else {
+        unreachable!("required arguments are missing");
+    };
 
-    let subsonic_tracks = subsonic::fetch_subsonic_songs(&subsonic_client).await;
+    // Which collections to import is worked out before fetching the library, so a playlist that
+    // doesn't exist fails right away
+    let collections = if args.all_playlists {
+        let filter = PlaylistFilter {
+            include_name: args.include_name,
+            exclude_name: args.exclude_name,
+            include_owners: args.include_owner,
+            exclude_owners: args.exclude_owner,
+            collaborative: args.collaborative,
+        };
 
-    let playlist_id = match PlaylistId::from_id_or_uri(&args.playlist) {
-        Ok(id) => id,
-        Err(e) => {
-            println!("Error converting playlist to ID {}", e);
+        let playlists = source.list_collections().await.unwrap_or_else(|e| {
+            println!("Error listing playlists! {e}");
             std::process::exit(1);
+        });
+
+        let total = playlists.len();
+        let playlists: Vec<_> = playlists
+            .into_iter()
+            .filter(|playlist| filter.matches(playlist))
+            .collect();
+        println!("Importing {}/{total} playlists:", playlists.len());
+        for playlist in &playlists {
+            println!("  {} ({} tracks)", playlist.name, playlist.total);
         }
+
+        playlists
+    } else {
+        let id = match (&args.source, args.playlist) {
+            (ImportSource::Liked, _) => CollectionId::SavedTracks,
+            (ImportSource::Playlist, Some(playlist)) => CollectionId::Playlist(playlist),
+            // Only sources holding a single playlist get this far without one
+            (ImportSource::Playlist, None) => {
+                let mut playlists = source.list_collections().await.unwrap_or_default();
+                if playlists.len() != 1 {
+                    println!("--playlist is required when importing a playlist");
+                    std::process::exit(1);
+                }
+                playlists.remove(0).id
+            }
+        };
+
+        let metadata = source.collection_metadata(&id).await.unwrap_or_else(|e| {
+            println!("Error fetching tracks! {e}");
+            std::process::exit(1);
+        });
+        vec![metadata]
     };
 
-    let spotify_playlist = match spotify_client
-        .playlist(playlist_id.clone(), None, None)
-        .await
-    {
-        Ok(playlist) => playlist,
-        Err(e) => panic!("{e}"),
+    // Prompting is impossible without someone at a terminal to answer (eg. cron, CI or containers)
+    let on_missing = match args.on_missing {
+        MissingPolicy::Prompt if !std::io::stdin().is_terminal() => {
+            println!(
+                "stdin is not a terminal, missing tracks will be reported instead of prompted"
+            );
+            MissingPolicy::Report
+        }
+        policy => policy,
     };
 
-    println!("{BOLD}{GREEN}=== Importing Playlist ==={RESET}");
-    println!("Name: {}", spotify_playlist.name);
-    println!("Total Tracks: {}", spotify_playlist.tracks.total);
+    let history = ImportHistory::load(ImportHistory::default_path()).unwrap_or_else(|e| {
+        println!("Error loading import history! {e}");
+        std::process::exit(1);
+    });
 
-    let mut spotify_tracks = Vec::new();
-    let mut offset = 0;
+    let options = ImportOptions {
+        on_missing,
+        on_existing: match args.sync {
+            true => ExistingPolicy::Update,
+            false => args.on_existing,
+        },
+        target: args.destination,
+        description_template: args.description_template,
+    };
 
-    // Turn all spotify tracks into a Track type and add them to the collection
-    loop {
-        let page = spotify_client
-            .playlist_items_manual(playlist_id.clone(), None, None, Some(50), Some(offset))
-            .await
-            .expect("Failed to get page of tracks from spotify!");
-
-        for item in page.items {
-            if let Some(PlayableItem::Track(track)) = item.track {
-                // Turn source track into a Track
-                if let Ok(track) = track.try_into() {
-                    spotify_tracks.push(track);
+    let cache = match (args.refresh_cache, args.no_cache) {
+        (_, true) => CacheMode::Off,
+        (true, false) => CacheMode::Refresh,
+        (false, false) => CacheMode::Use,
+    };
+    let subsonic = SubsonicDestination::login(
+        subsonic_url,
+        subsonic_user,
+        subsonic_password,
+        cache,
+        args.library_scan,
+    );
+
+    let failed = match args.playlist_dir {
+        Some(dir) => {
+            let importer = Importer::new(
+                PlaylistFiles::new(
+                    subsonic,
+                    dir,
+                    args.playlist_format,
+                    args.library_root.as_deref().map(LibraryRoot::parse),
+                    args.keep_server_playlist,
+                ),
+                match_config,
+                mappings,
+                history,
+                options,
+            )
+            .await;
+            importer
+                .import_all(&source, collections, args.all_playlists)
+                .await
+        }
+        None => {
+            let importer = Importer::new(subsonic, match_config, mappings, history, options).await;
+            importer
+                .import_all(&source, collections, args.all_playlists)
+                .await
+        }
+    };
+
+    if failed > 0 {
+        if args.all_playlists {
+            println!("{failed} playlists weren't imported");
+        }
+        std::process::exit(1);
+    }
+}
+
+impl<D: Destination> Importer<D> {
+    /// Fetches the library of the destination, every import of the run is matched against it
+    async fn new(
+        destination: D,
+        match_config: MatchConfig,
+        mappings: MappingStore,
+        history: ImportHistory,
+        options: ImportOptions,
+    ) -> Self {
+        let library = destination.list_library().await.unwrap_or_else(|e| {
+            println!("{e}");
+            std::process::exit(1);
+        });
+        let library = Library::new(library);
+        println!("Loaded {} songs from subsonic", library.len());
+
+        Self {
+            destination,
+            library,
+            match_config,
+            mappings: RefCell::new(mappings),
+            history: RefCell::new(history),
+            options,
+        }
+    }
+
+    /// Imports each collection in turn, returns how many of them weren't imported
+    async fn import_all(
+        &self,
+        source: &impl SourceProvider,
+        collections: Vec<CollectionMetadata>,
+        refetch_metadata: bool,
+    ) -> usize {
+        let mut failed = 0;
+        for metadata in collections {
+            println!();
+            match metadata.id {
+                CollectionId::Playlist(_) => {
+                    println!("{BOLD}{GREEN}=== Importing Playlist ==={RESET}")
+                }
+                CollectionId::SavedTracks => {
+                    println!("{BOLD}{GREEN}=== Importing Liked Songs ==={RESET}")
                 }
             }
-        }
 
-        if page.next.is_none() {
-            break;
+            // Playlists listed in bulk can lack details (eg. spotify leaves out descriptions)
+            let metadata = match refetch_metadata {
+                true => source.collection_metadata(&metadata.id).await,
+                false => Ok(metadata),
+            };
+
+            let collection = match metadata {
+                Ok(metadata) => source.fetch_collection(metadata).await,
+                Err(e) => Err(e),
+            };
+
+            let imported = match collection {
+                Ok(collection) => self.import(source.name(), collection).await,
+                Err(e) => {
+                    println!("Error fetching tracks! {e}");
+                    false
+                }
+            };
+
+            if !imported {
+                failed += 1;
+            }
         }
 
-        offset += 50
+        failed
     }
 
-    // Do a first pass to see how many tracks can be confidently matched.
-    // It's important to keep the exact order of the playlist, including unmatched tracks
-    // so that a later pass can use those unmatched tracks to prompt the user for input.
-    let partially_matched_playlist: Vec<Track> = spotify_tracks
-        .into_iter()
-        .map(|track| search(track, &subsonic_tracks))
-        .collect();
+    /// Matches every track of a collection against the library and adds the matches to a new
+    /// playlist or the favorites.
+    ///
+    /// Returns false if nothing was imported, either because tracks were missing and the missing
+    /// policy is to fail, or because subsonic returned an error.
+    async fn import(&self, provider: &str, collection: Collection) -> bool {
+        println!("Name: {}", collection.metadata.name);
+        println!("Total Tracks: {}", collection.metadata.total);
 
-    let mut playlist: Vec<Track> =
-        futures::stream::iter(partially_matched_playlist.into_iter())
-            .then(|track| {
-                let client = &subsonic_client;
-                async move {
-                    // If the track source is spotify, it failed to match in the first pass
-                    // prompt the user for input on how to handle the track.
-                    // Returns the new track if it could be found and the same old track if not.
-                    match track.track_source == TrackSource::Spotify {
-                        true => {
-                            // Separate each prompt slightly
-                            println!();
-                            prompt_user(&track, client).await
-                        }
-                        false => Some(track),
+        // Checked before matching, so a refused import doesn't ask about missing tracks first
+        let existing = match self.options.target {
+            TrackDestination::Playlist => {
+                match self.find_existing(provider, &collection.metadata).await {
+                    Ok(existing) => existing,
+                    Err(e) => {
+                        println!("Error looking for earlier imports! {e}");
+                        return false;
                     }
                 }
+            }
+            TrackDestination::Favorites => None,
+        };
+
+        if existing.is_some() && self.options.on_existing == ExistingPolicy::Refuse {
+            println!(
+                "'{}' was already imported, use --on-existing to replace or update it",
+                collection.metadata.name
+            );
+            return false;
+        }
+
+        // Every track the collection has, even those that won't be matched, so favorites added for
+        // a track are kept while the collection still has it
+        let source_ids: HashSet<String> = collection
+            .tracks
+            .iter()
+            .map(|track| track.id.clone())
+            .collect();
+
+        // Do a first pass to see how many tracks can be confidently matched.
+        // It's important to keep the exact order of the playlist, including unmatched tracks
+        // so that a later pass can use those unmatched tracks to prompt the user for input.
+        let partially_matched_playlist: Vec<SearchResult> = collection
+            .tracks
+            .into_iter()
+            .map(|track| {
+                let result = search(
+                    track,
+                    &self.library,
+                    &self.match_config,
+                    &self.mappings.borrow(),
+                );
+
+                if result.is_match() {
+                    print_match(&result);
+                }
+
+                if result.confidence == Confidence::Ignored {
+                    println!(
+                        "{YELLOW}Skipped{RESET} '{}' by '{}', it's always skipped",
+                        result.source.title, result.source.artist
+                    );
+                }
+
+                // Let the user know when the chosen match only narrowly beat another track
+                if result.is_ambiguous() {
+                    warn_ambiguous(&result);
+                }
+
+                result
+            })
+            .collect();
+
+        let missing: Vec<Track> = partially_matched_playlist
+            .iter()
+            .filter(|result| !result.is_match() && result.confidence != Confidence::Ignored)
+            .map(|result| result.source.clone())
+            .collect();
+
+        if self.options.on_missing == MissingPolicy::Fail && !missing.is_empty() {
+            print_missing(&missing);
+            println!("Nothing was imported since some tracks are missing");
+            return false;
+        }
+
+        // Paired with the ID of the source track they were resolved for
+        let mut resolved: Vec<(String, Track)> = futures::stream::iter(partially_matched_playlist)
+            .then(|result| async move {
+                let source = result.source.id.clone();
+                self.resolve(result).await.map(|track| (source, track))
             })
             .flat_map(futures::stream::iter)
             .collect()
             .await;
 
-    // Remove all remaining unmatched tracks. Navidrome specifically has an issue with keeping
-    // song index in playlists if invalid ID's are provided in the playlist creation
-    playlist.retain(|track| track.track_source == TrackSource::Subsonic);
-    let playlist_length = playlist.len();
-
-    // Finally, add the songs to either a new playlist or the favorites
-    if args.destination == TrackDestination::Favorites {
-        match add_songs_to_favorites(&subsonic_client, playlist).await {
-            Ok(_) => {
-                println!();
-                println!("{BOLD}{GREEN}=== Songs added! ==={RESET}");
-                println!(
-                    "{playlist_length}/{} Songs matched!",
-                    spotify_playlist.tracks.total
-                );
+        if let Err(e) = self.mappings.borrow().save() {
+            println!("Error saving mappings! {e}");
+        }
+
+        // Remove all remaining unmatched tracks. Navidrome specifically has an issue with keeping
+        // song index in playlists if invalid ID's are provided in the playlist creation
+        resolved.retain(|(_, track)| track.track_source == TrackSource::Library);
+        let playlist: Vec<Track> = resolved.iter().map(|(_, track)| track.clone()).collect();
+        let playlist_length = playlist.len();
+
+        // Finally, add the songs to either a new playlist or the favorites
+        let imported = if self.options.target == TrackDestination::Favorites {
+            match self
+                .save_favorites(provider, &collection.metadata, &source_ids, &resolved)
+                .await
+            {
+                Ok(done) => {
+                    println!();
+                    println!("{BOLD}{GREEN}=== {done} ==={RESET}");
+                    println!(
+                        "{playlist_length}/{} Songs matched!",
+                        collection.metadata.total
+                    );
+                    true
+                }
+                Err(e) => {
+                    println!("Error adding songs to favorites! {e}");
+                    false
+                }
             }
-            Err(e) => println!("Error adding songs to favorites! {e}"),
+        } else {
+            match self
+                .save_playlist(provider, &collection.metadata, existing, &playlist)
+                .await
+            {
+                Ok(done) => {
+                    println!();
+                    println!("{BOLD}{GREEN}=== {done} ==={RESET}");
+                    println!(
+                        "{playlist_length}/{} Songs matched!",
+                        collection.metadata.total
+                    );
+                    true
+                }
+                Err(e) => {
+                    println!("Error saving playlist! {e}");
+                    false
+                }
+            }
+        };
+
+        if self.options.on_missing == MissingPolicy::Report {
+            print_missing(&missing);
         }
-    } else {
-        match subsonic::create_playlist(
-            &subsonic_client,
-            spotify_playlist.name,
-            spotify_playlist.description.unwrap_or(String::new()),
-            playlist,
-        )
-        .await
+
+        imported
+    }
+
+    /// Adds the matched tracks to a new playlist, or replaces or updates the playlist of an
+    /// earlier import of the collection. Returns what was done, for the summary.
+    async fn save_playlist(
+        &self,
+        provider: &str,
+        metadata: &CollectionMetadata,
+        existing: Option<PlaylistSummary>,
+        tracks: &[Track],
+    ) -> Result<&'static str, String> {
+        let marker = SourceMarker::new(provider, metadata);
+        let description = marker.describe(&self.options.description_template, metadata);
+        let comment = marker.comment(&description);
+
+        let (id, done) = match (existing, self.options.on_existing) {
+            (Some(existing), ExistingPolicy::Update) => {
+                let current = self.destination.playlist_tracks(&existing.id).await?;
+                let diff = PlaylistDiff::new(&current, tracks);
+                print_diff(&diff);
+
+                // The name and comment are written back even when the tracks are the same, so the
+                // marker records the latest snapshot and import
+                if !diff.is_empty() || existing.name != metadata.name || existing.comment != comment
+                {
+                    self.destination
+                        .update_playlist(&existing.id, &metadata.name, &comment, tracks)
+                        .await?;
+                }
+                (existing.id, "Playlist updated!")
+            }
+            // Refused imports stop before matching, so the playlist is replaced. The old playlist
+            // is only deleted once the new one was created, so a failure doesn't lose it
+            (Some(existing), _) => {
+                let id = self
+                    .destination
+                    .create_playlist(&metadata.name, &comment, tracks)
+                    .await?;
+                // The new playlist is remembered either way, the old one can be deleted by hand
+                if id != existing.id
+                    && let Err(e) = self.destination.delete_playlist(&existing.id).await
+                {
+                    println!("Error deleting the old playlist '{}'! {e}", existing.name);
+                }
+                (id, "Playlist replaced!")
+            }
+            (None, _) => {
+                let id = self
+                    .destination
+                    .create_playlist(&metadata.name, &comment, tracks)
+                    .await?;
+                (id, "Playlist created!")
+            }
+        };
+
+        // Remembered so later imports find the playlist, even if its comment is changed
+        let mut history = self.history.borrow_mut();
+        history.insert_playlist(ImportedPlaylist {
+            provider: provider.to_string(),
+            collection: marker.playlist,
+            destination: self.destination.id(),
+            playlist_id: id,
+            name: metadata.name.clone(),
+        });
+        if let Err(e) = history.save() {
+            println!("Error saving import history! {e}");
+        }
+
+        Ok(done)
+    }
+
+    /// Adds the matched tracks to the favorites, remembering which of them weren't favorites
+    /// yet. When syncing, songs an earlier import added for tracks the collection no longer has
+    /// are removed from the favorites again, favorites the user added themselves are left alone.
+    /// Returns what was done, for the summary.
+    async fn save_favorites(
+        &self,
+        provider: &str,
+        metadata: &CollectionMetadata,
+        source_ids: &HashSet<String>,
+        matched: &[(String, Track)],
+    ) -> Result<&'static str, String> {
+        let sync = self.options.on_existing == ExistingPolicy::Update;
+        let favorites = self.destination.list_favorites().await?;
+        let favorite_ids: HashSet<&str> = favorites.iter().map(|song| song.id.as_str()).collect();
+
+        let collection = metadata.id.to_string();
+        let destination = self.destination.id();
+        let mut starred = StarredSongs {
+            tracks: self
+                .history
+                .borrow()
+                .starred(provider, &collection, &destination),
+            provider: provider.to_string(),
+            collection,
+            destination,
+        };
+
+        let source: HashSet<&str> = source_ids.iter().map(String::as_str).collect();
+        let pairs: Vec<(&str, &str)> = matched
+            .iter()
+            .map(|(source, track)| (source.as_str(), track.id.as_str()))
+            .collect();
+        let (added, removed) = starred.update(&source, &pairs, &favorite_ids, sync);
+
+        let added: Vec<Track> = added
+            .iter()
+            .filter_map(|id| matched.iter().find(|(_, track)| &track.id == id))
+            .map(|(_, track)| track.clone())
+            .collect();
+        // Songs another collection's import also added stay while that collection has them
+        let removed: Vec<Track> = favorites
+            .iter()
+            .filter(|song| {
+                removed.contains(&song.id)
+                    && !self.history.borrow().starred_by_other(&starred, &song.id)
+            })
+            .cloned()
+            .collect();
+
+        if sync {
+            print_diff(&PlaylistDiff {
+                added: added.clone(),
+                removed: removed.clone(),
+                reordered: false,
+            });
+        }
+
+        if !added.is_empty() {
+            self.destination.add_favorites(&added).await?;
+        }
+        if !removed.is_empty() {
+            self.destination.remove_favorites(&removed).await?;
+        }
+
+        let mut history = self.history.borrow_mut();
+        history.set_starred(starred);
+        if let Err(e) = history.save() {
+            println!("Error saving import history! {e}");
+        }
+
+        Ok(match sync {
+            true => "Favorites synced!",
+            false => "Songs added!",
+        })
+    }
+
+    /// The playlist an earlier import of a collection created, if it still exists. Found by the
+    /// import history, or otherwise by the marker in its comment (eg. when imported on another
+    /// machine).
+    async fn find_existing(
+        &self,
+        provider: &str,
+        metadata: &CollectionMetadata,
+    ) -> Result<Option<PlaylistSummary>, String> {
+        let collection = metadata.id.to_string();
+        let stored = self
+            .history
+            .borrow()
+            .playlist(provider, &collection, &self.destination.id())
+            .map(str::to_string);
+
+        let playlists = self.destination.list_playlists().await?;
+        let existing = playlists
+            .iter()
+            .find(|playlist| stored.as_ref() == Some(&playlist.id))
+            .or_else(|| {
+                playlists.iter().find(|playlist| {
+                    SourceMarker::parse(&playlist.comment)
+                        .is_some_and(|marker| marker.is_from(provider, &collection))
+                })
+            });
+
+        Ok(existing.cloned())
+    }
+
+    /// The second pass over a track, deciding what to use for it when it didn't match by itself
+    async fn resolve(&self, result: SearchResult<'_>) -> Option<Track> {
+        if result.is_match() {
+            return Some(result.track);
+        }
+
+        if result.confidence == Confidence::Ignored {
+            return None;
+        }
+
+        match self.options.on_missing {
+            MissingPolicy::Prompt => {}
+            MissingPolicy::BestGuess => return best_guess(&result),
+            _ => return None,
+        }
+
+        // Nothing can be answered once stdin is closed, so the remaining tracks aren't asked about
+        if STDIN_CLOSED.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // Separate each prompt slightly
+        println!();
+
+        // Tracks that nearly matched only need to be confirmed by the user
+        if let Some(suggestion) = result.suggestion()
+            && confirm_match(&result.source, suggestion)
         {
-            Ok(_) => {
-                println!();
-                println!("{BOLD}{GREEN}=== Playlist created! ==={RESET}");
-                println!(
-                    "{playlist_length}/{} Songs matched!",
-                    spotify_playlist.tracks.total
-                );
+            let mapping = Mapping::Song(suggestion.track.id.clone());
+            self.mappings.borrow_mut().insert(&result.source, mapping);
+            return Some(suggestion.track.clone());
+        }
+
+        // If the track failed to match in the first pass prompt the user for
+        // input on how to handle the track.
+        // Returns the new track if it could be found and nothing if not.
+        match prompt_user(&result, &self.destination, &self.match_config).await {
+            Resolution::Found(track) => {
+                let mapping = Mapping::Song(track.id.clone());
+                self.mappings.borrow_mut().insert(&result.source, mapping);
+                Some(*track)
             }
-            Err(e) => println!("Error during playlist creation! {e}"),
+            Resolution::AlwaysSkip => {
+                self.mappings
+                    .borrow_mut()
+                    .insert(&result.source, Mapping::Skip);
+                None
+            }
+            Resolution::Skip => None,
         }
     }
 }
 
-/// Called for every track that failed to match. Asks the user how they want to proceed. Options include:
+/// Called for every missing track when guessing is allowed. Uses the best candidate, if there is one.
+fn best_guess(result: &SearchResult) -> Option<Track> {
+    let guess = result.candidates.first()?;
+
+    println!(
+        "{YELLOW}Guessed{RESET} '{}' by '{}' is {}",
+        result.source.title,
+        result.source.artist,
+        describe(guess.track)
+    );
+    println!("  {}", guess.breakdown);
+
+    Some(guess.track.clone())
+}
+
+/// Lists every track that couldn't be matched automatically
+fn print_missing(missing: &[Track]) {
+    if missing.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{BOLD}{YELLOW}=== Missing tracks ==={RESET}");
+    for track in missing {
+        println!("{}", describe(track));
+    }
+}
+
+/// Lists what syncing changes in a playlist, before the changes are applied
+fn print_diff(diff: &PlaylistDiff) {
+    println!();
+    println!("{BOLD}{YELLOW}=== Changes ==={RESET}");
+    if diff.is_empty() {
+        println!("Already up to date");
+        return;
+    }
+
+    for track in &diff.added {
+        println!("{GREEN}+{RESET} {}", describe(track));
+    }
+    for track in &diff.removed {
+        println!("{YELLOW}-{RESET} {}", describe(track));
+    }
+    if diff.reordered {
+        println!("~ Tracks were reordered");
+    }
+    println!("{} added, {} removed", diff.added.len(), diff.removed.len());
+}
+
+/// Called for every track that matched. Shows which track it was matched to and why, so a wrong
+/// match can be traced back to the criteria that caused it.
+fn print_match(result: &SearchResult) {
+    let Some(best) = result.candidates.first() else {
+        return;
+    };
+
+    println!(
+        "{GREEN}Matched{RESET} '{}' by '{}' to '{}' by '{}' from '{}' ({})",
+        result.source.title,
+        result.source.artist,
+        best.track.title,
+        best.track.artist,
+        best.track.album,
+        best.track.id
+    );
+    println!("  {}", best.breakdown);
+}
+
+/// Called for every track that matched, but had other candidates that scored almost as high.
+/// Lists the close candidates so a wrong match can be spotted and fixed afterwards.
+fn warn_ambiguous(result: &SearchResult) {
+    println!("{BOLD}{YELLOW}=== Ambiguous match! ==={RESET}");
+    println!(
+        "Matched '{}' by '{}' from '{}', but other tracks scored closely:",
+        result.track.title, result.track.artist, result.track.album
+    );
+
+    for candidate in result.contenders() {
+        println!(
+            "  '{}' by '{}' from '{}' ({})",
+            candidate.track.title,
+            candidate.track.artist,
+            candidate.track.album,
+            candidate.track.id
+        );
+        println!("    {}", candidate.breakdown);
+    }
+    println!();
+}
+
+/// Called for every track whose best candidate scored just below the threshold. Suggests the
+/// candidate and asks the user to confirm it, pressing enter accepts the suggestion.
+///
+/// Returns whether the suggestion was accepted.
+fn confirm_match(source: &Track, suggestion: &Candidate) -> bool {
+    println!("{BOLD}{YELLOW}=== Possible match! ==={RESET}");
+    println!("Looking for {}", describe(source));
+    println!("Did you mean {}?", describe(suggestion.track));
+    println!("  {}", suggestion.breakdown);
+    print!("[{BOLD}{GREEN}Y{RESET}]es. [{BOLD}N{RESET}]o: ");
+
+    // Default option, nothing can be confirmed once stdin is closed
+    matches!(
+        read_input().map(|input| input.to_uppercase()).as_deref(),
+        Some("" | "Y")
+    )
+}
+
+/// Called for every track that failed to match. Lists the best candidates from the library and
+/// asks the user how they want to proceed. Options include:
 /// - Skip the track entirely, no track will be added to the created playlist.
+/// - Always skip, like skip but the track will also be skipped in every later import.
+/// - Pick one of the listed candidates by its number.
+/// - Find, search subsonic with a query of the user's choosing and list those results instead.
 /// - Enter ID, the user is prompted to enter the track id from the target platform manually.
 /// - Download, currently unimplemented. Unsure of how to handle this at the moment.
 ///
-/// Returns an Option<Track>, containing Some() if a track could be resolved and None if no track could be
-/// resolved from subsonic.
-async fn prompt_user(missing_track: &Track, client: &Client) -> Option<Track> {
+/// Returns the track if one could be resolved from subsonic, or whether the track should be skipped.
+async fn prompt_user(
+    missing: &SearchResult<'_>,
+    destination: &impl Destination,
+    config: &MatchConfig,
+) -> Resolution {
+    let missing_track = &missing.source;
+
     println!("{BOLD}{YELLOW}=== Missing track! ==={RESET}");
-    println!(
-        "Can't find '{}' by '{}'",
-        missing_track.title, missing_track.artist
-    );
-    println!("What would you like to do?");
-    print!("[{BOLD}{GREEN}S{RESET}]kip. Enter [{BOLD}I{RESET}]d: ");
+    println!("Can't find {}", describe(missing_track));
 
-    // Flush stdout so we can read from the same line as the available options
-    let _ = std::io::stdout().flush();
-    let mut input = String::new();
-    std::io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read stdin!");
-    // Remove trailing new line and capatalize
-    let input = &input[..input.len() - 1].to_uppercase();
-
-    // Default option
-    if input.is_empty() || input.eq("S") {
-        return None;
+    let mut candidates: Vec<(Track, Match)> = missing
+        .candidates
+        .iter()
+        .take(PROMPT_CANDIDATES)
+        .map(|candidate| (candidate.track.clone(), candidate.breakdown))
+        .collect();
+
+    loop {
+        print_candidates(&candidates);
+
+        println!("What would you like to do?");
+        print!("[{BOLD}{GREEN}S{RESET}]kip. [{BOLD}A{RESET}]lways skip. ");
+        if !candidates.is_empty() {
+            print!("Pick [{BOLD}1-{}{RESET}]. ", candidates.len());
+        }
+        print!("[{BOLD}F{RESET}]ind. Enter [{BOLD}I{RESET}]d: ");
+
+        // Skip once stdin is closed
+        let Some(input) = read_input().map(|input| input.to_uppercase()) else {
+            return Resolution::Skip;
+        };
+
+        // Default option
+        if input.is_empty() || input.eq("S") {
+            return Resolution::Skip;
+        }
+
+        if input.eq("A") {
+            return Resolution::AlwaysSkip;
+        }
+
+        // Prompt to input tacks subsonic id
+        if input.eq("I") {
+            print!("Please enter the subsonic ID of the track: ");
+            let Some(id) = read_input() else {
+                return Resolution::Skip;
+            };
+
+            // Check for that song on subsonic
+            return match destination.get_track(&id).await {
+                Some(track) => Resolution::Found(Box::new(track)),
+                None => Resolution::Skip,
+            };
+        }
+
+        // Search subsonic again and list those results instead
+        if input.eq("F") {
+            print!("Search for: ");
+            let Some(query) = read_input() else {
+                return Resolution::Skip;
+            };
+
+            let mut results: Vec<(Track, Match)> = destination
+                .search(&query)
+                .await
+                .into_iter()
+                .map(|track| {
+                    let breakdown = Track::match_tracks(missing_track, &track, config);
+                    (track, breakdown)
+                })
+                .collect();
+            results.sort_by_key(|(_, breakdown)| std::cmp::Reverse(breakdown.score()));
+            results.truncate(PROMPT_CANDIDATES);
+
+            if results.is_empty() {
+                println!("No songs found for '{query}'");
+            } else {
+                candidates = results;
+            }
+            continue;
+        }
+
+        match input.parse::<usize>() {
+            Ok(number) if (1..=candidates.len()).contains(&number) => {
+                return Resolution::Found(Box::new(candidates.swap_remove(number - 1).0));
+            }
+            _ => println!("Invalid option '{input}'"),
+        }
     }
+}
+
+/// Runs one of the mappings subcommands and saves the changes
+fn manage_mappings(mappings: &mut MappingStore, action: MappingAction) -> Result<(), String> {
+    match action {
+        MappingAction::List => {
+            if mappings.iter().next().is_none() {
+                println!("No tracks remembered in {}", mappings.path().display());
+            }
 
-    // Prompt to input tacks subsonic id
-    if input.eq("I") {
-        let mut id = String::new();
-        print!("Please enter the subsonic ID of the track: ");
-        let _ = std::io::stdout().flush();
-        std::io::stdin()
-            .read_line(&mut id)
-            .expect("Failed to read stdin!");
+            for (id, entry) in mappings.iter() {
+                let target = match &entry.mapping {
+                    Mapping::Song(song) => format!("{GREEN}{song}{RESET}"),
+                    Mapping::Skip => format!("{YELLOW}always skipped{RESET}"),
+                };
 
-        // Check for that song on subsonic
-        return get_song(client, &id[..id.len() - 1]).await;
+                match entry.title.is_empty() {
+                    true => println!("{id} -> {target}"),
+                    false => println!("{id} '{}' by '{}' -> {target}", entry.title, entry.artist),
+                }
+            }
+            return Ok(());
+        }
+        MappingAction::Set {
+            source_id,
+            subsonic_id,
+        } => {
+            let key = track_key(mappings, &source_id)?;
+            mappings.set(&key, Mapping::Song(subsonic_id));
+        }
+        MappingAction::Skip { source_id } => {
+            let key = track_key(mappings, &source_id)?;
+            mappings.set(&key, Mapping::Skip);
+        }
+        MappingAction::Remove { source_id } => {
+            if !mappings.remove(&track_key(mappings, &source_id)?) {
+                println!("No mapping found for {source_id}");
+                return Ok(());
+            }
+        }
+        MappingAction::Clear => mappings.clear(),
     }
 
-    None
+    mappings
+        .save()
+        .map_err(|e| format!("Error saving mappings! {e}"))
+}
+
+/// Mappings are keyed the same way tracks are identified when importing. IDs that are already
+/// remembered and IDs of other sources (eg. "csv:Artist - Title") are used as they are, while
+/// spotify IDs and URIs given on the command line both refer to the same track
+fn track_key(mappings: &MappingStore, source_id: &str) -> Result<String, String> {
+    match mappings.contains(source_id) || !spotify::is_track_id(source_id) {
+        true => Ok(source_id.to_string()),
+        false => spotify::track_uri(source_id),
+    }
+}
+
+/// Lists candidates numbered from 1, with enough detail to tell different versions apart
+fn print_candidates(candidates: &[(Track, Match)]) {
+    if candidates.is_empty() {
+        println!("No candidates found in the library");
+        return;
+    }
+
+    println!("Candidates:");
+    for (number, (track, breakdown)) in candidates.iter().enumerate() {
+        println!("  [{BOLD}{}{RESET}] {}", number + 1, describe(track));
+        println!("      {breakdown}");
+    }
+}
+
+/// Describes a track as "'Title' by 'Artist' from 'Album' (1995, 4:13, flac)"
+fn describe(track: &Track) -> String {
+    let mut details = vec![
+        track.year.to_string(),
+        format!("{}:{:02}", track.duration / 60, track.duration % 60),
+    ];
+
+    if let Some(format) = &track.format {
+        details.push(format.clone());
+    }
+
+    format!(
+        "'{}' by '{}' from '{}' ({})",
+        track.title,
+        track.artist,
+        track.album,
+        details.join(", ")
+    )
+}
+
+/// Set once stdin is closed, every later prompt is skipped
+static STDIN_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Reads a line from stdin, printed prompts are flushed first so the input can be read from the
+/// same line. The trailing new line and any surrounding whitespace are removed.
+///
+/// Returns None if stdin was closed or couldn't be read.
+fn read_input() -> Option<String> {
+    let _ = std::io::stdout().flush();
+    if STDIN_CLOSED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut input = String::new();
+    match std::io::stdin().read_line(&mut input) {
+        Ok(0) => {
+            STDIN_CLOSED.store(true, Ordering::Relaxed);
+            println!();
+            println!("stdin was closed, skipping every remaining missing track");
+            None
+        }
+        Ok(_) => Some(input.trim().to_string()),
+        Err(e) => {
+            println!();
+            println!("Failed to read stdin! {e}");
+            None
+        }
+    }
 }
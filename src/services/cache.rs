@@ -0,0 +1,258 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::Track;
+
+/// Whether the library is read from the cache on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Use the cache, only fetching what changed since it was saved
+    #[default]
+    Use,
+    /// Fetch the whole library and save it to the cache
+    Refresh,
+    /// Fetch the whole library without reading or saving the cache
+    Off,
+}
+
+/// An album as the server lists it, without its songs. When the server lists an album differently
+/// than it was cached (eg. a song was added or removed), its songs have to be fetched again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlbumSummary {
+    pub id: String,
+    pub name: String,
+    pub year: Option<i32>,
+    pub song_count: i32,
+    pub duration: i32,
+}
+
+/// The songs of an album, along with how the album was listed when they were fetched
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedAlbum {
+    pub album: AlbumSummary,
+    pub tracks: Vec<Track>,
+}
+
+/// The songs of a library saved on disk, so later runs only have to fetch what changed instead of
+/// the whole library. Every server and user has its own cache.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LibraryCache {
+    /// The library the songs were fetched from, see `Destination::id`
+    pub library: String,
+    /// When the library last changed according to the server, in milliseconds since the epoch
+    pub last_modified: Option<i64>,
+    pub albums: Vec<CachedAlbum>,
+}
+
+impl LibraryCache {
+    /// Where the cache of a library is saved, inside the user's cache directory
+    pub fn path(library: &str) -> PathBuf {
+        let name: String = library
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c,
+                false => '_',
+            })
+            .collect();
+
+        dirs::cache_dir()
+            .unwrap_or_default()
+            .join("TuneTracker")
+            .join(format!("library-{name}.json"))
+    }
+
+    /// Load the cache of a library. A missing or unreadable cache (eg. written by an older
+    /// version) is treated as no cache, the library is then fetched again.
+    pub fn load(library: &str) -> Option<Self> {
+        let contents = std::fs::read_to_string(Self::path(library)).ok()?;
+
+        serde_json::from_str::<Self>(&contents)
+            .ok()
+            .filter(|cache| cache.library == library)
+    }
+
+    /// Every song of the library, album by album
+    pub fn tracks(self) -> Vec<Track> {
+        self.albums
+            .into_iter()
+            .flat_map(|album| album.tracks)
+            .collect()
+    }
+
+    /// Bring the cache in line with the albums the server currently lists. Albums that are gone or
+    /// listed differently are dropped, and the IDs of the albums whose songs have to be fetched
+    /// (again) are returned.
+    ///
+    /// Returns nothing when the cache can't be brought up to date this way and the whole library
+    /// has to be fetched: when it has songs that aren't on a known album, or when the library
+    /// changed but every album is listed the same (eg. a song was retagged or its file moved).
+    pub fn reconcile(&mut self, current: &[AlbumSummary]) -> Option<Vec<String>> {
+        if self.albums.iter().any(|cached| cached.album.id.is_empty()) {
+            return None;
+        }
+
+        let listed: HashMap<&str, &AlbumSummary> = current
+            .iter()
+            .map(|album| (album.id.as_str(), album))
+            .collect();
+
+        let previous = self.albums.len();
+        self.albums
+            .retain(|cached| listed.get(cached.album.id.as_str()) == Some(&&cached.album));
+
+        let kept: HashSet<&str> = self
+            .albums
+            .iter()
+            .map(|cached| cached.album.id.as_str())
+            .collect();
+        let changed: Vec<String> = current
+            .iter()
+            .filter(|album| !kept.contains(album.id.as_str()))
+            .map(|album| album.id.clone())
+            .collect();
+
+        match changed.is_empty() && self.albums.len() == previous {
+            true => None,
+            false => Some(changed),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = Self::path(&self.library);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        }
+
+        let contents = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(&path, contents)
+            .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{TrackSource, test_track};
+
+    #[test]
+    fn test_library_cache() {
+        // Every server and user gets a cache of their own
+        let path = LibraryCache::path("jimmy@https://navidrome.example.com");
+        assert!(path.ends_with("library-jimmy_https___navidrome_example_com.json"));
+        assert_ne!(
+            path,
+            LibraryCache::path("robert@https://navidrome.example.com")
+        );
+
+        let track = Track {
+            duration: 508,
+            track_number: 6,
+            disc_number: 1,
+            year: 2015,
+            original_year: Some(1975),
+            id: String::from("a1b2c3"),
+            isrc: Some(String::from("USAT21500106")),
+            format: Some(String::from("flac")),
+            path: Some(String::from(
+                "Led Zeppelin/Physical Graffiti/06 Kashmir.flac",
+            )),
+            track_source: TrackSource::Library,
+            ..test_track("Kashmir", "Led Zeppelin", "Physical Graffiti")
+        };
+        let physical_graffiti = AlbumSummary {
+            id: String::from("al-1"),
+            name: String::from("Physical Graffiti"),
+            year: Some(2015),
+            song_count: 1,
+            duration: 508,
+        };
+        let cache = LibraryCache {
+            library: String::from("jimmy@https://navidrome.example.com"),
+            last_modified: Some(1_700_000_000_000),
+            albums: vec![CachedAlbum {
+                album: physical_graffiti.clone(),
+                tracks: vec![track.clone()],
+            }],
+        };
+
+        let json = serde_json::to_string(&cache).unwrap();
+        let cached: LibraryCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(cached.albums, cache.albums);
+        assert_eq!(cached.last_modified, cache.last_modified);
+        assert_eq!(cached.tracks(), vec![track.clone()]);
+
+        // Caches written before songs were kept by album are fetched again
+        let old = r#"{"library": "jimmy@https://navidrome.example.com", "last_modified": null,
+            "newest_album": null, "tracks": []}"#;
+        assert!(serde_json::from_str::<LibraryCache>(old).is_err());
+    }
+
+    #[test]
+    fn test_library_cache_refresh() {
+        let album = |id: &str, song_count: i32| AlbumSummary {
+            id: String::from(id),
+            name: format!("Album {id}"),
+            year: Some(1975),
+            song_count,
+            duration: song_count * 200,
+        };
+        let cached = |album: AlbumSummary| CachedAlbum {
+            tracks: (0..album.song_count)
+                .map(|i| Track {
+                    id: format!("{}-{i}", album.id),
+                    ..Default::default()
+                })
+                .collect(),
+            album,
+        };
+        let library = || LibraryCache {
+            library: String::from("jimmy@https://navidrome.example.com"),
+            last_modified: None,
+            albums: vec![
+                cached(album("kept", 2)),
+                cached(album("removed", 1)),
+                cached(album("changed", 3)),
+            ],
+        };
+
+        // Removed albums are dropped, changed and new albums are fetched again
+        let mut cache = library();
+        let changed = cache
+            .reconcile(&[album("new", 1), album("kept", 2), album("changed", 4)])
+            .unwrap();
+        assert_eq!(changed, vec![String::from("new"), String::from("changed")]);
+        assert_eq!(
+            cache
+                .tracks()
+                .iter()
+                .map(|t| t.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["kept-0", "kept-1"]
+        );
+
+        // Albums that are only gone don't need anything fetched
+        let mut cache = library();
+        let changed = cache
+            .reconcile(&[album("kept", 2), album("changed", 3)])
+            .unwrap();
+        assert!(changed.is_empty());
+        assert_eq!(cache.albums.len(), 2);
+
+        // A change that doesn't show in the album list can't be narrowed down
+        let mut cache = library();
+        assert_eq!(
+            cache.reconcile(&[album("kept", 2), album("removed", 1), album("changed", 3)]),
+            None
+        );
+
+        // Neither can songs that weren't found on any album
+        let mut cache = library();
+        cache.albums.push(cached(AlbumSummary::default()));
+        assert_eq!(cache.reconcile(&[album("kept", 2)]), None);
+    }
+}
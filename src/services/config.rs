@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Weights and thresholds used when matching tracks. Every criterion awards up to its weight in
+/// points, a track is considered a match once it reaches the threshold.
+///
+/// Can be loaded from a TOML file, any missing keys keep their default value:
+/// ```toml
+/// # Years are unreliable after remaster retagging
+/// year_weight = 0
+/// threshold = 65
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    pub title_weight: i32,
+    pub artist_weight: i32,
+    pub album_weight: i32,
+    pub year_weight: i32,
+    /// Awarded when the ISRC matches and the album matches at least slightly
+    pub isrc_weight: i32,
+    /// Awarded when the durations are exactly equal
+    pub duration_exact_weight: i32,
+    /// Awarded when the durations are within `duration_tolerance` seconds of each other
+    pub duration_near_weight: i32,
+    /// How many seconds apart two durations can be while still counting as a match
+    pub duration_tolerance: i32,
+    pub track_number_weight: i32,
+    /// Subtracted for every version qualifier only one of the tracks has (eg. Live vs studio)
+    pub qualifier_conflict_penalty: i32,
+    /// Tracks scoring this many points or higher are matched without asking. Both thresholds are
+    /// lowered in proportion for tracks without a duration, year or track number (eg. from a data
+    /// export), since those can't score
+    pub threshold: i32,
+    /// Tracks scoring this many points or higher, but below `threshold`, are suggested to the
+    /// user to confirm. Anything lower is treated as missing
+    pub confirm_threshold: i32,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            title_weight: 20,
+            artist_weight: 20,
+            album_weight: 20,
+            year_weight: 10,
+            // A matching ISRC (on a matching album) is almost certainly the same recording, so it's
+            // enough to push a track over the threshold on its own
+            isrc_weight: 100,
+            duration_exact_weight: 20,
+            duration_near_weight: 10,
+            duration_tolerance: 3,
+            track_number_weight: 20,
+            // Enough to let the right version win over a wrong version of an otherwise identical track
+            qualifier_conflict_penalty: 25,
+            threshold: 70,
+            confirm_threshold: 50,
+        }
+    }
+}
+
+impl MatchConfig {
+    /// Load a config from a TOML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+        toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {e}", path.display()))
+    }
+
+    /// Override a single setting, given as "name=value" (eg. "year_weight=0")
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or(format!("Expected name=value, got '{setting}'"))?;
+
+        let value: i32 = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{value}' is not a number"))?;
+
+        let field = match name.trim() {
+            "title_weight" => &mut self.title_weight,
+            "artist_weight" => &mut self.artist_weight,
+            "album_weight" => &mut self.album_weight,
+            "year_weight" => &mut self.year_weight,
+            "isrc_weight" => &mut self.isrc_weight,
+            "duration_exact_weight" => &mut self.duration_exact_weight,
+            "duration_near_weight" => &mut self.duration_near_weight,
+            "duration_tolerance" => &mut self.duration_tolerance,
+            "track_number_weight" => &mut self.track_number_weight,
+            "qualifier_conflict_penalty" => &mut self.qualifier_conflict_penalty,
+            "threshold" => &mut self.threshold,
+            "confirm_threshold" => &mut self.confirm_threshold,
+            name => return Err(format!("Unknown setting '{name}'")),
+        };
+
+        *field = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_config() {
+        // Missing keys keep their defaults
+        let config: MatchConfig = toml::from_str("year_weight = 0\nthreshold = 60").unwrap();
+        assert_eq!(config.year_weight, 0);
+        assert_eq!(config.threshold, 60);
+        assert_eq!(config.title_weight, MatchConfig::default().title_weight);
+        assert!(toml::from_str::<MatchConfig>("unknown_weight = 5").is_err());
+
+        let mut config = MatchConfig::default();
+        config.set("year_weight=0").unwrap();
+        assert_eq!(config.year_weight, 0);
+        assert!(config.set("year_weight").is_err());
+        assert!(config.set("year_weight=ten").is_err());
+        assert!(config.set("unknown_weight=5").is_err());
+    }
+}
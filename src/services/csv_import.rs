@@ -0,0 +1,442 @@
+use std::{io::Read, path::Path};
+
+use serde::Deserialize;
+
+use crate::services::{
+    Track, TrackSource, release_year,
+    source::{Capabilities, Collection, CollectionId, CollectionMetadata, SourceProvider},
+    split_artists, spotify,
+};
+
+/// Which columns of a CSV file hold which details of a track, found by their header. Defaults to
+/// the headers of an Exportify export. Columns missing from a file are treated as unknown, only
+/// the title and artist are required.
+///
+/// Can be loaded from a TOML file, any missing keys keep their default value:
+/// ```toml
+/// # TuneMyMusic
+/// title = "Track name"
+/// artist = "Artist name"
+/// album = "Album"
+/// playlist = "Playlist name"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvColumns {
+    /// The spotify track URI or ID, used to remember tracks resolved by hand
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// "1982", "1982-11" or "1982-11-19"
+    pub release_date: String,
+    pub disc_number: String,
+    pub track_number: String,
+    pub duration: String,
+    pub isrc: String,
+    /// Files holding several playlists name the playlist of every track, otherwise the whole file
+    /// is a single playlist named after the file
+    pub playlist: String,
+    /// Separates the artists in the artist column. A separator followed by a space is taken to be
+    /// part of a name, so "Tyler, The Creator,Kali Uchis" is two artists. Credits like "feat." are
+    /// split too, the same way as for any other source
+    pub artist_separator: String,
+    /// Whether durations are in milliseconds instead of seconds. Durations written as "m:ss" are
+    /// always understood
+    pub duration_in_ms: bool,
+    /// Whether track numbers restart on every disc, like they do on spotify
+    pub per_disc_track_numbers: bool,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        Self {
+            id: String::from("Track URI"),
+            title: String::from("Track Name"),
+            artist: String::from("Artist Name(s)"),
+            album: String::from("Album Name"),
+            release_date: String::from("Album Release Date"),
+            disc_number: String::from("Disc Number"),
+            track_number: String::from("Track Number"),
+            duration: String::from("Track Duration (ms)"),
+            isrc: String::from("ISRC"),
+            playlist: String::from("Playlist name"),
+            artist_separator: String::from(","),
+            duration_in_ms: true,
+            per_disc_track_numbers: spotify::CAPABILITIES.per_disc_track_numbers,
+        }
+    }
+}
+
+impl CsvColumns {
+    /// Load the columns from a TOML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid CSV columns {}: {e}", path.display()))
+    }
+
+    /// Override a single column, given as "name=header" (eg. "title=Song")
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or(format!("Expected name=header, got '{setting}'"))?;
+
+        let value = value.trim().to_string();
+        let field = match name.trim() {
+            "id" => &mut self.id,
+            "title" => &mut self.title,
+            "artist" => &mut self.artist,
+            "album" => &mut self.album,
+            "release_date" => &mut self.release_date,
+            "disc_number" => &mut self.disc_number,
+            "track_number" => &mut self.track_number,
+            "duration" => &mut self.duration,
+            "isrc" => &mut self.isrc,
+            "playlist" => &mut self.playlist,
+            "artist_separator" => &mut self.artist_separator,
+            "duration_in_ms" => {
+                self.duration_in_ms = parse_bool(&value)?;
+                return Ok(());
+            }
+            "per_disc_track_numbers" => {
+                self.per_disc_track_numbers = parse_bool(&value)?;
+                return Ok(());
+            }
+            name => return Err(format!("Unknown column '{name}'")),
+        };
+
+        *field = value;
+        Ok(())
+    }
+}
+
+/// The playlists of a CSV file, in the order they first appear
+#[derive(Debug, Default)]
+pub struct CsvPlaylists {
+    playlists: Vec<Collection>,
+    capabilities: Capabilities,
+}
+
+impl CsvPlaylists {
+    /// Read a CSV file, a file without a playlist column is a single playlist named after the file
+    pub fn load(path: &Path, columns: &CsvColumns) -> Result<Self, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+
+        Self::parse(file, &name, columns)
+            .map_err(|e| format!("Invalid CSV {}: {e}", path.display()))
+    }
+
+    pub fn parse(reader: impl Read, name: &str, columns: &CsvColumns) -> Result<Self, String> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+        let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+        let column = |header: &str| {
+            headers
+                .iter()
+                .position(|h| !header.is_empty() && h.trim().eq_ignore_ascii_case(header.trim()))
+        };
+
+        let title = column(&columns.title).ok_or(format!("No '{}' column", columns.title))?;
+        let artist = column(&columns.artist).ok_or(format!("No '{}' column", columns.artist))?;
+        let album = column(&columns.album);
+        let id = column(&columns.id);
+        let release_date = column(&columns.release_date);
+        let disc_number = column(&columns.disc_number);
+        let track_number = column(&columns.track_number);
+        let duration = column(&columns.duration);
+        let isrc = column(&columns.isrc);
+        let playlist = column(&columns.playlist);
+
+        let capabilities = Capabilities {
+            per_disc_track_numbers: columns.per_disc_track_numbers,
+        };
+        let mut playlists = Self {
+            playlists: Vec::new(),
+            capabilities,
+        };
+        for record in reader.records() {
+            let record = record.map_err(|e| e.to_string())?;
+            let field = |index: Option<usize>| {
+                index
+                    .and_then(|i| record.get(i))
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+            };
+
+            let collection = playlists.collection(field(playlist).unwrap_or(name));
+            collection.metadata.total += 1;
+
+            // Rows without a title or artist can't be matched
+            let (Some(title), Some(artist)) = (field(Some(title)), field(Some(artist))) else {
+                continue;
+            };
+
+            let artists = split_credit(artist, &columns.artist_separator);
+
+            collection.tracks.push(Track {
+                title: title.to_string(),
+                artist: artists.join(", "),
+                // Files without IDs still need a stable way to remember tracks resolved by hand
+                id: field(id)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("csv:{artist} - {title}")),
+                artists,
+                album: field(album).unwrap_or_default().to_string(),
+                duration: field(duration)
+                    .map(|value| parse_duration(value, columns.duration_in_ms))
+                    .unwrap_or_default(),
+                track_number: field(track_number)
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_default(),
+                disc_number: field(disc_number)
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_default(),
+                year: release_year(field(release_date), None),
+                original_year: None,
+                isrc: field(isrc).map(str::to_uppercase),
+                musicbrainz_id: None,
+                format: None,
+                path: None,
+                track_source: TrackSource::Source(capabilities),
+            });
+        }
+
+        Ok(playlists)
+    }
+
+    // Playlists are identified by their name since CSV files don't include playlist IDs. Rows
+    // naming the same playlist are one playlist, so no two playlists share a name
+    fn find(&self, name: &str) -> Result<&Collection, String> {
+        self.playlists
+            .iter()
+            .find(|playlist| playlist.metadata.name == name)
+            .or_else(|| {
+                self.playlists
+                    .iter()
+                    .find(|playlist| playlist.metadata.name.eq_ignore_ascii_case(name))
+            })
+            .ok_or(format!("No playlist named '{name}' in the CSV file"))
+    }
+
+    fn collection(&mut self, name: &str) -> &mut Collection {
+        let index = match self.playlists.iter().position(|p| p.metadata.name == name) {
+            Some(index) => index,
+            None => {
+                self.playlists.push(Collection {
+                    metadata: CollectionMetadata {
+                        id: CollectionId::Playlist(name.to_string()),
+                        name: name.to_string(),
+                        description: String::new(),
+                        owner_id: String::new(),
+                        owner_name: None,
+                        collaborative: false,
+                        snapshot: None,
+                        total: 0,
+                    },
+                    tracks: Vec::new(),
+                });
+                self.playlists.len() - 1
+            }
+        };
+
+        &mut self.playlists[index]
+    }
+}
+
+impl SourceProvider for CsvPlaylists {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    async fn list_collections(&self) -> Result<Vec<CollectionMetadata>, String> {
+        Ok(self
+            .playlists
+            .iter()
+            .map(|playlist| playlist.metadata.clone())
+            .collect())
+    }
+
+    /// Saved tracks are every track of the file, for files exported from Liked Songs
+    async fn collection_metadata(&self, id: &CollectionId) -> Result<CollectionMetadata, String> {
+        match id {
+            CollectionId::Playlist(name) => self.find(name).map(|p| p.metadata.clone()),
+            CollectionId::SavedTracks => Ok(CollectionMetadata {
+                id: CollectionId::SavedTracks,
+                name: String::from("Liked Songs"),
+                description: String::new(),
+                owner_id: String::new(),
+                owner_name: None,
+                collaborative: false,
+                snapshot: None,
+                total: self.playlists.iter().map(|p| p.metadata.total).sum(),
+            }),
+        }
+    }
+
+    async fn fetch_tracks(&self, id: &CollectionId) -> Result<Vec<Track>, String> {
+        match id {
+            CollectionId::Playlist(name) => self.find(name).map(|p| p.tracks.clone()),
+            CollectionId::SavedTracks => Ok(self
+                .playlists
+                .iter()
+                .flat_map(|playlist| playlist.tracks.iter().cloned())
+                .collect()),
+        }
+    }
+}
+
+// Splits the artist column into the individual artists. Exportify joins artists with a bare ","
+// while names like "Earth, Wind & Fire" have a space after their comma, so separators followed by
+// whitespace are left alone.
+fn split_credit(credit: &str, separator: &str) -> Vec<String> {
+    if separator.is_empty() {
+        return split_artists(credit);
+    }
+
+    let mut credits = Vec::new();
+    let mut start = 0;
+    for (index, _) in credit.match_indices(separator) {
+        let end = index + separator.len();
+        if credit[end..].starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        credits.push(&credit[start..index]);
+        start = end;
+    }
+    credits.push(&credit[start..]);
+
+    credits.into_iter().flat_map(split_artists).collect()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{value}' is not true or false"))
+}
+
+// Durations are either a number of (milli)seconds or written as "m:ss"
+fn parse_duration(value: &str, in_ms: bool) -> i32 {
+    if let Some((minutes, seconds)) = value.split_once(':') {
+        let minutes: i32 = minutes.parse().unwrap_or_default();
+        let seconds: i32 = seconds.parse().unwrap_or_default();
+        return minutes * 60 + seconds;
+    }
+
+    let value: f64 = value.parse().unwrap_or_default();
+    match in_ms {
+        true => (value / 1000.0) as i32,
+        false => value as i32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{spotify, test_track};
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_csv_import() {
+        // Exportify
+        let exportify = "\
+Track URI,Track Name,Artist Name(s),Album Name,Album Release Date,Disc Number,Track Number,Track Duration (ms),ISRC
+spotify:track:xxx,St. Tristan's Sword - Rough Mix,Led Zeppelin,Coda (Deluxe Edition),1982-11-19,3,3,341000,usat21500101
+spotify:track:yyy,Stay With Me,\"Sam Smith,Mary J. Blige\",In The Lonely Hour,2014,1,4,172724,
+,,,,,,,,
+";
+        let csv =
+            CsvPlaylists::parse(exportify.as_bytes(), "Coda", &CsvColumns::default()).unwrap();
+        let coda = CollectionId::Playlist(String::from("coda"));
+        assert_eq!(block_on(csv.collection_metadata(&coda)).unwrap().total, 3);
+        let tracks = block_on(csv.fetch_tracks(&coda)).unwrap();
+        assert_eq!(
+            tracks[0],
+            Track {
+                duration: 341,
+                track_number: 3,
+                disc_number: 3,
+                year: 1982,
+                id: String::from("spotify:track:xxx"),
+                isrc: Some(String::from("USAT21500101")),
+                track_source: TrackSource::Source(spotify::CAPABILITIES),
+                ..test_track(
+                    "St. Tristan's Sword - Rough Mix",
+                    "Led Zeppelin",
+                    "Coda (Deluxe Edition)",
+                )
+            }
+        );
+        assert_eq!(tracks[1].artist, "Sam Smith, Mary J. Blige");
+        assert_eq!(tracks[1].artists.len(), 2);
+        assert_eq!(tracks[1].isrc, None);
+
+        // TuneMyMusic, several playlists in one file without IDs or durations
+        let mut columns = CsvColumns::default();
+        columns.set("title=Track name").unwrap();
+        columns.set("artist=Artist name").unwrap();
+        columns.set("album=Album").unwrap();
+        assert!(columns.set("genre=Genre").is_err());
+        assert!(columns.set("duration_in_ms=maybe").is_err());
+        columns.set("per_disc_track_numbers=false").unwrap();
+
+        let tunemymusic = "\
+Track name,Artist name,Album,Playlist name,Type,ISRC
+Black Dog,Led Zeppelin,Led Zeppelin IV,Rock,Playlist,
+Ramble On,Led Zeppelin,Led Zeppelin II,Road Trip,Playlist,
+Whole Lotta Love,Led Zeppelin,Led Zeppelin II,Rock,Playlist,
+";
+        let csv = CsvPlaylists::parse(tunemymusic.as_bytes(), "export", &columns).unwrap();
+        let names: Vec<String> = block_on(csv.list_collections())
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(names, ["Rock", "Road Trip"]);
+        let rock = CollectionId::Playlist(String::from("Rock"));
+        assert_eq!(block_on(csv.fetch_tracks(&rock)).unwrap().len(), 2);
+        assert!(!csv.capabilities().per_disc_track_numbers);
+        let saved = block_on(csv.fetch_tracks(&CollectionId::SavedTracks)).unwrap();
+        assert_eq!(saved.len(), 3);
+        let road_trip = CollectionId::Playlist(String::from("Road Trip"));
+        assert_eq!(
+            block_on(csv.fetch_tracks(&road_trip)).unwrap()[0].id,
+            "csv:Led Zeppelin - Ramble On"
+        );
+
+        assert!(
+            CsvPlaylists::parse(tunemymusic.as_bytes(), "export", &CsvColumns::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_artist_separator() {
+        // Commas followed by a space are part of a name
+        assert_eq!(
+            split_credit("Tyler, The Creator,Kali Uchis", ","),
+            vec!["Tyler, The Creator", "Kali Uchis"]
+        );
+        assert_eq!(
+            split_credit("Earth, Wind & Fire", ","),
+            vec!["Earth, Wind & Fire"]
+        );
+        assert_eq!(
+            split_credit("Sam Smith,Mary J. Blige feat. Mark Ronson", ","),
+            vec!["Sam Smith", "Mary J. Blige", "Mark Ronson"]
+        );
+        assert_eq!(
+            split_credit("Queen|David Bowie", "|"),
+            vec!["Queen", "David Bowie"]
+        );
+        assert_eq!(split_credit(",,Queen,", ","), vec!["Queen"]);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::services::Track;
+
+/// A playlist of a destination, without its tracks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: String,
+    pub comment: String,
+}
+
+/// Where matched tracks end up, along with the library they're matched against. New backends
+/// (eg. other media servers) only need to implement this trait to be imported into.
+pub trait Destination {
+    /// Identifies the library, so playlists created in it can be found again by a later import
+    /// (eg. the server URL and user)
+    fn id(&self) -> String;
+
+    /// Every song in the library, tracks are only matched against these
+    async fn list_library(&self) -> Result<Vec<Track>, String>;
+
+    /// A single song of the library, if there is one with this ID
+    async fn get_track(&self, id: &str) -> Option<Track>;
+
+    /// Songs of the library matching a query typed by the user
+    async fn search(&self, query: &str) -> Vec<Track>;
+
+    /// Create a playlist holding the tracks in order, returns the ID of the new playlist
+    async fn create_playlist(
+        &self,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<String, String>;
+
+    /// Every playlist the user can see
+    async fn list_playlists(&self) -> Result<Vec<PlaylistSummary>, String>;
+
+    /// The tracks of a playlist, in order
+    async fn playlist_tracks(&self, id: &str) -> Result<Vec<Track>, String>;
+
+    /// Replace the name, comment and tracks of an existing playlist
+    async fn update_playlist(
+        &self,
+        id: &str,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<(), String>;
+
+    /// Delete a playlist
+    async fn delete_playlist(&self, id: &str) -> Result<(), String>;
+
+    /// Every song in the user's favorites
+    async fn list_favorites(&self) -> Result<Vec<Track>, String>;
+
+    /// Add the tracks to the user's favorites
+    async fn add_favorites(&self, tracks: &[Track]) -> Result<(), String>;
+
+    /// Remove the tracks from the user's favorites
+    async fn remove_favorites(&self, tracks: &[Track]) -> Result<(), String>;
+}
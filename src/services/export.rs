@@ -0,0 +1,440 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::services::{
+    Track, TrackSource,
+    source::{Capabilities, CollectionId, CollectionMetadata, SourceProvider},
+    split_artists,
+};
+
+/// The playlists and saved tracks of a Spotify "Download your data" export, read straight from
+/// its JSON files so nothing has to be fetched from spotify.
+///
+/// The export only has the title, artist, album and URI of each track, so tracks read from it
+/// lack an ISRC, duration, track number and year.
+#[derive(Debug, Default)]
+pub struct SpotifyExport {
+    playlists: Vec<ExportedPlaylist>,
+    saved_tracks: Vec<Track>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistFile {
+    playlists: Vec<ExportedPlaylist>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedPlaylist {
+    /// Identifies the playlist within the export, see `SpotifyExport::add_playlists`
+    #[serde(skip)]
+    id: String,
+    name: String,
+    #[serde(default, rename = "lastModifiedDate")]
+    last_modified: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    collaborators: Vec<String>,
+    #[serde(default)]
+    items: Vec<PlaylistItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaylistItem {
+    // Episodes and local files have no track
+    #[serde(default)]
+    track: Option<PlaylistTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistTrack {
+    track_name: String,
+    artist_name: String,
+    album_name: String,
+    track_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LibraryFile {
+    #[serde(default)]
+    tracks: Vec<LibraryTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LibraryTrack {
+    track: String,
+    artist: String,
+    album: String,
+    uri: String,
+}
+
+impl SpotifyExport {
+    /// Read an export from the directory it was extracted to, or from a single one of its files.
+    /// Playlists are read from every "Playlist*.json" and saved tracks from "YourLibrary.json".
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let files = match path.is_dir() {
+            true => {
+                let entries = std::fs::read_dir(path)
+                    .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+                let mut files: Vec<_> = entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+                    .collect();
+
+                files.sort_by_key(|file| file_order(file));
+                files
+            }
+            false => vec![path.to_path_buf()],
+        };
+
+        let mut export = Self::default();
+        for file in files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            let is_library = name.starts_with("YourLibrary");
+            if !is_library && !name.starts_with("Playlist") {
+                continue;
+            }
+
+            let contents = std::fs::read_to_string(&file)
+                .map_err(|e| format!("Failed to read {}: {e}", file.display()))?;
+
+            let added = match is_library {
+                true => export.add_library(&contents),
+                false => export.add_playlists(&contents),
+            };
+            added.map_err(|e| format!("Invalid export file {}: {e}", file.display()))?;
+        }
+
+        if export.playlists.is_empty() && export.saved_tracks.is_empty() {
+            return Err(format!(
+                "No Playlist*.json or YourLibrary.json found in {}",
+                path.display()
+            ));
+        }
+
+        Ok(export)
+    }
+
+    /// Add the playlists of a "Playlist1.json" file.
+    ///
+    /// Exports don't include playlist IDs, so playlists are identified by their name. Playlists
+    /// sharing a name are told apart by the date they were last modified, eg.
+    /// "Road Trip (2024-01-31)", so each of them is imported on its own.
+    pub fn add_playlists(&mut self, json: &str) -> Result<(), String> {
+        let file: PlaylistFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        self.playlists.extend(file.playlists);
+        self.assign_ids();
+        Ok(())
+    }
+
+    // IDs made up for playlists sharing a name never equal the name of another playlist, so a
+    // playlist actually named "Road Trip (2024-01-31)" can't be mistaken for one of them. Should
+    // they still clash (eg. two playlists modified on the same day), a number is added.
+    fn assign_ids(&mut self) {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for playlist in &self.playlists {
+            *counts.entry(&playlist.name).or_default() += 1;
+        }
+
+        let names: HashSet<String> = counts.keys().map(|name| name.to_string()).collect();
+        let shared: HashSet<String> = counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name.to_string())
+            .collect();
+
+        let mut taken = HashSet::new();
+        for playlist in &mut self.playlists {
+            if !shared.contains(&playlist.name) {
+                playlist.id = playlist.name.clone();
+                continue;
+            }
+
+            let modified = playlist.last_modified.as_deref().unwrap_or("undated");
+            let base = format!("{} ({modified})", playlist.name);
+            let mut id = base.clone();
+            let mut number = 1;
+            while names.contains(&id) || taken.contains(&id) {
+                number += 1;
+                id = format!("{base} #{number}");
+            }
+
+            taken.insert(id.clone());
+            playlist.id = id;
+        }
+    }
+
+    /// Add the saved tracks of a "YourLibrary.json" file
+    pub fn add_library(&mut self, json: &str) -> Result<(), String> {
+        let file: LibraryFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        self.saved_tracks.extend(
+            file.tracks
+                .into_iter()
+                .map(|track| exported_track(track.track, track.artist, track.album, track.uri)),
+        );
+        Ok(())
+    }
+
+    // Finds a playlist by its name, or its name and date for playlists sharing a name
+    fn find(&self, id: &str) -> Result<&ExportedPlaylist, String> {
+        let found = self
+            .playlists
+            .iter()
+            .find(|playlist| playlist.id == id)
+            .or_else(|| {
+                self.playlists
+                    .iter()
+                    .find(|playlist| playlist.id.eq_ignore_ascii_case(id))
+            });
+        if let Some(playlist) = found {
+            return Ok(playlist);
+        }
+
+        let shared: Vec<&str> = self
+            .playlists
+            .iter()
+            .filter(|playlist| playlist.name.eq_ignore_ascii_case(id))
+            .map(|playlist| playlist.id.as_str())
+            .collect();
+        match shared.is_empty() {
+            true => Err(format!("No playlist named '{id}' in the export")),
+            false => Err(format!(
+                "Several playlists are named '{id}' in the export, pick one of: {}",
+                shared.join(", ")
+            )),
+        }
+    }
+}
+
+impl SourceProvider for SpotifyExport {
+    fn name(&self) -> &'static str {
+        "spotify-export"
+    }
+
+    /// Exports don't include track numbers
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    async fn list_collections(&self) -> Result<Vec<CollectionMetadata>, String> {
+        Ok(self
+            .playlists
+            .iter()
+            .map(ExportedPlaylist::metadata)
+            .collect())
+    }
+
+    async fn collection_metadata(&self, id: &CollectionId) -> Result<CollectionMetadata, String> {
+        match id {
+            CollectionId::Playlist(name) => self.find(name).map(ExportedPlaylist::metadata),
+            CollectionId::SavedTracks => Ok(CollectionMetadata {
+                id: CollectionId::SavedTracks,
+                name: String::from("Liked Songs"),
+                description: String::new(),
+                owner_id: String::new(),
+                owner_name: None,
+                collaborative: false,
+                snapshot: None,
+                total: self.saved_tracks.len() as u32,
+            }),
+        }
+    }
+
+    async fn fetch_tracks(&self, id: &CollectionId) -> Result<Vec<Track>, String> {
+        let playlist = match id {
+            CollectionId::Playlist(name) => self.find(name)?,
+            CollectionId::SavedTracks => return Ok(self.saved_tracks.clone()),
+        };
+
+        Ok(playlist
+            .items
+            .iter()
+            .filter_map(|item| item.track.as_ref())
+            .map(|track| {
+                exported_track(
+                    track.track_name.clone(),
+                    track.artist_name.clone(),
+                    track.album_name.clone(),
+                    track.track_uri.clone(),
+                )
+            })
+            .collect())
+    }
+}
+
+impl ExportedPlaylist {
+    // Exports don't say who owns a playlist, so the owner is left empty
+    fn metadata(&self) -> CollectionMetadata {
+        CollectionMetadata {
+            id: CollectionId::Playlist(self.id.clone()),
+            name: self.name.clone(),
+            description: self.description.clone().unwrap_or_default(),
+            owner_id: String::new(),
+            owner_name: None,
+            collaborative: !self.collaborators.is_empty(),
+            snapshot: None,
+            total: self.items.len() as u32,
+        }
+    }
+}
+
+// The export only credits the main artist of a track. The track URI is used as the ID, the same
+// way tracks fetched from spotify are identified.
+fn exported_track(title: String, artist: String, album: String, uri: String) -> Track {
+    Track {
+        title,
+        artists: split_artists(&artist),
+        artist,
+        album,
+        duration: 0,
+        track_number: 0,
+        disc_number: 0,
+        year: 0,
+        original_year: None,
+        id: uri,
+        isrc: None,
+        musicbrainz_id: None,
+        format: None,
+        path: None,
+        track_source: TrackSource::Source(Capabilities::default()),
+    }
+}
+
+// Sorts "Playlist10.json" after "Playlist9.json" instead of after "Playlist1.json"
+fn file_order(file: &Path) -> (String, u32) {
+    let name = file.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = name[prefix.len()..].parse().unwrap_or_default();
+
+    (prefix.to_string(), number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_data_export() {
+        let mut export = SpotifyExport::default();
+        export
+            .add_playlists(
+                r#"{"playlists": [{
+                    "name": "Zeppelin",
+                    "lastModifiedDate": "2024-01-01",
+                    "collaborators": [],
+                    "description": null,
+                    "numberOfFollowers": 0,
+                    "items": [
+                        {
+                            "track": {
+                                "trackName": "Ramble On - Remaster",
+                                "artistName": "Led Zeppelin",
+                                "albumName": "Led Zeppelin II (Remaster)",
+                                "trackUri": "spotify:track:xxx"
+                            },
+                            "episode": null,
+                            "localTrack": null,
+                            "addedDate": "2024-01-01"
+                        },
+                        {"track": null, "episode": {"episodeName": "Podcast"}, "addedDate": "2024-01-01"}
+                    ]
+                }]}"#,
+            )
+            .unwrap();
+        export
+            .add_library(
+                r#"{"tracks": [{
+                    "artist": "Led Zeppelin",
+                    "album": "Led Zeppelin II (Remaster)",
+                    "track": "Ramble On - Remaster",
+                    "uri": "spotify:track:xxx"
+                }], "albums": []}"#,
+            )
+            .unwrap();
+        assert!(export.add_playlists("{}").is_err());
+
+        let summaries = block_on(export.list_collections()).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].total, 2);
+
+        // Playlists are found by name, episodes are left out
+        let zeppelin = CollectionId::Playlist(String::from("zeppelin"));
+        assert_eq!(
+            block_on(export.collection_metadata(&zeppelin))
+                .unwrap()
+                .total,
+            2
+        );
+        let tracks = block_on(export.fetch_tracks(&zeppelin)).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(
+            tracks,
+            block_on(export.fetch_tracks(&CollectionId::SavedTracks)).unwrap()
+        );
+        let missing = CollectionId::Playlist(String::from("Led Zeppelin"));
+        assert!(block_on(export.collection_metadata(&missing)).is_err());
+
+        // Playlists sharing a name are each imported on their own, told apart by the date they
+        // were modified. A playlist that happens to be named like that keeps its name
+        export
+            .add_playlists(
+                r#"{"playlists": [
+                    {"name": "Zeppelin", "lastModifiedDate": "2024-02-01", "items": [{"track": {
+                        "trackName": "Kashmir",
+                        "artistName": "Led Zeppelin",
+                        "albumName": "Physical Graffiti",
+                        "trackUri": "spotify:track:yyy"
+                    }}]},
+                    {"name": "Zeppelin (2024-02-01)", "items": []},
+                    {"name": "Zeppelin", "lastModifiedDate": "2024-02-01", "items": []}
+                ]}"#,
+            )
+            .unwrap();
+        let ids: Vec<CollectionId> = block_on(export.list_collections())
+            .unwrap()
+            .into_iter()
+            .map(|summary| summary.id)
+            .collect();
+        let playlist = |id: &str| CollectionId::Playlist(String::from(id));
+        assert_eq!(
+            ids,
+            vec![
+                playlist("Zeppelin (2024-01-01)"),
+                playlist("Zeppelin (2024-02-01) #2"),
+                playlist("Zeppelin (2024-02-01)"),
+                playlist("Zeppelin (2024-02-01) #3"),
+            ]
+        );
+        let second = playlist("Zeppelin (2024-02-01) #2");
+        let kashmir = block_on(export.fetch_tracks(&second)).unwrap();
+        assert_eq!(kashmir[0].id, "spotify:track:yyy");
+        assert_eq!(
+            block_on(export.collection_metadata(&second)).unwrap().name,
+            "Zeppelin"
+        );
+        assert!(
+            block_on(export.fetch_tracks(&playlist("Zeppelin (2024-02-01)")))
+                .unwrap()
+                .is_empty()
+        );
+
+        // The name alone doesn't say which one to import
+        let error = block_on(export.collection_metadata(&zeppelin)).unwrap_err();
+        assert!(error.contains("Zeppelin (2024-01-01), Zeppelin (2024-02-01) #2"));
+
+        // Exports lack everything but the title, artist, album and URI
+        let track = &tracks[0];
+        assert_eq!(track.id, "spotify:track:xxx");
+        assert_eq!(track.duration, 0);
+        assert_eq!(track.track_number, 0);
+        assert_eq!(track.year, 0);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use crate::services::{Track, normalize::normalize};
+
+/// Words that are on too many tracks to say anything about whether two tracks match
+const STOPWORDS: [&str; 18] = [
+    "a", "an", "the", "of", "and", "or", "in", "on", "at", "to", "for", "from", "with", "by", "de",
+    "la", "le", "el",
+];
+
+/// Artists and words listed on more tracks than this (eg. "Various Artists" or "love") would pull
+/// in a large part of the library, so they're only looked up when a track has nothing rarer
+const COMMON_KEY_LIMIT: usize = 1000;
+
+/// The tracks of a subsonic library, indexed so that searching for a track only has to score
+/// the handful of tracks that could plausibly be a match instead of the whole library.
+#[derive(Debug, Default)]
+pub struct Library {
+    tracks: Vec<Track>,
+    by_id: HashMap<String, usize>,
+    by_isrc: HashMap<String, Vec<usize>>,
+    by_musicbrainz_id: HashMap<String, Vec<usize>>,
+    by_artist: HashMap<String, Vec<usize>>,
+    by_word: HashMap<String, Vec<usize>>,
+}
+
+impl Library {
+    pub fn new(tracks: Vec<Track>) -> Self {
+        let mut library = Self::default();
+
+        for (index, track) in tracks.iter().enumerate() {
+            library.by_id.insert(track.id.clone(), index);
+
+            if let Some(isrc) = &track.isrc {
+                insert(&mut library.by_isrc, isrc.to_uppercase(), index);
+            }
+
+            if let Some(musicbrainz_id) = &track.musicbrainz_id {
+                insert(
+                    &mut library.by_musicbrainz_id,
+                    musicbrainz_id.clone(),
+                    index,
+                );
+            }
+
+            for artist in &track.artists {
+                insert(&mut library.by_artist, normalize(artist), index);
+            }
+
+            for word in words(track) {
+                insert(&mut library.by_word, word, index);
+            }
+        }
+
+        library.tracks = tracks;
+        library
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Every track of the library, in library order
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Find a track by its subsonic ID
+    pub fn get(&self, id: &str) -> Option<&Track> {
+        self.by_id.get(id).map(|&i| &self.tracks[i])
+    }
+
+    /// Returns every track that shares an ISRC, MusicBrainz ID, artist or a word of the title or album
+    /// with the source track, in library order. Stopwords aren't indexed, and artists or words that
+    /// are on a large part of the library are skipped unless the track has nothing rarer.
+    ///
+    /// Tracks that share nothing with the source track rarely get anywhere near the threshold,
+    /// but a typo in both the title and artist can still be made up for by the rest of the track.
+    /// So this only narrows down the tracks worth scoring first, see `search`.
+    pub fn candidates(&self, source: &Track) -> Vec<&Track> {
+        let mut indices: Vec<usize> = Vec::new();
+
+        if let Some(isrc) = &source.isrc {
+            lookup(&self.by_isrc, &isrc.to_uppercase(), &mut indices);
+        }
+
+        if let Some(musicbrainz_id) = &source.musicbrainz_id {
+            lookup(&self.by_musicbrainz_id, musicbrainz_id, &mut indices);
+        }
+
+        let artists = source
+            .artists
+            .iter()
+            .filter_map(|artist| self.by_artist.get(&normalize(artist)));
+        let words = words(source)
+            .into_iter()
+            .filter_map(|word| self.by_word.get(&word));
+        let entries: Vec<&Vec<usize>> = artists.chain(words).collect();
+
+        let rare: Vec<&Vec<usize>> = entries
+            .iter()
+            .copied()
+            .filter(|entries| entries.len() <= COMMON_KEY_LIMIT)
+            .collect();
+        match rare.is_empty() {
+            true => {
+                if let Some(rarest) = entries.into_iter().min_by_key(|entries| entries.len()) {
+                    indices.extend(rarest);
+                }
+            }
+            false => rare.into_iter().for_each(|entries| indices.extend(entries)),
+        }
+
+        // Keep library order so that ties are resolved the same way as scanning the whole library
+        indices.sort_unstable();
+        indices.dedup();
+
+        indices.into_iter().map(|i| &self.tracks[i]).collect()
+    }
+}
+
+// Every normalized word of a tracks title and album, except stopwords
+fn words(track: &Track) -> Vec<String> {
+    let title = normalize(&track.title);
+    let album = normalize(&track.album);
+
+    title
+        .split(' ')
+        .chain(album.split(' '))
+        .filter(|word| !word.is_empty() && !STOPWORDS.contains(word))
+        .map(str::to_string)
+        .collect()
+}
+
+fn insert(index: &mut HashMap<String, Vec<usize>>, key: String, track: usize) {
+    let entries = index.entry(key).or_default();
+
+    // A track can contain the same word more than once
+    if entries.last() != Some(&track) {
+        entries.push(track);
+    }
+}
+
+fn lookup(index: &HashMap<String, Vec<usize>>, key: &str, indices: &mut Vec<usize>) {
+    if let Some(entries) = index.get(key) {
+        indices.extend(entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{
+        TrackSource, config::MatchConfig, mappings::MappingStore, rank, search, spotify, test_track,
+    };
+
+    #[test]
+    fn test_library_index() {
+        let config = MatchConfig::default();
+        let song = |title: &str, album: &str, track_number: u32, duration: i32| Track {
+            duration,
+            track_number,
+            disc_number: 1,
+            year: 1971,
+            id: String::from(title),
+            track_source: TrackSource::Library,
+            ..test_track(title, "Led Zeppelin", album)
+        };
+        let songs = vec![
+            song("Black Dog", "Led Zeppelin IV", 1, 295),
+            song("Rock and Roll", "Led Zeppelin IV", 2, 220),
+            song("Stairway to Heaven", "Led Zeppelin IV", 4, 482),
+            song("Black Dog (Live)", "The Song Remains the Same", 2, 330),
+            song("Whole Lotta Love", "Led Zeppelin II", 1, 334),
+            song("Ramble On", "Led Zeppelin II", 7, 273),
+            Track {
+                artists: vec![String::from("Robert Plant"), String::from("Alison Krauss")],
+                ..song("Please Read the Letter", "Raising Sand", 7, 353)
+            },
+        ];
+        let library = Library::new(songs.clone());
+        let from_spotify = |track: &Track| Track {
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..track.clone()
+        };
+
+        // The same songs from spotify, along with ones that differ in ways the index can't see:
+        // typos in both the title and artist on a differently named album, or nothing in common
+        // at all but the duration, track number and year
+        let mut sources: Vec<Track> = songs.iter().map(from_spotify).collect();
+        sources.extend([
+            Track {
+                title: String::from("Stairwey to Heavn"),
+                ..from_spotify(&songs[2])
+            },
+            Track {
+                title: String::from("Stairwey to Heavn"),
+                artists: vec![String::from("Led Zepelin")],
+                album: String::from("Untitled"),
+                ..from_spotify(&songs[2])
+            },
+            Track {
+                title: String::from("Blakc Dgo"),
+                artists: vec![String::from("Led Zepelin")],
+                album: String::from("Four Symbols"),
+                ..from_spotify(&songs[0])
+            },
+            Track {
+                title: String::from("Black Dog - Live"),
+                album: String::from("How the West Was Won"),
+                ..from_spotify(&songs[3])
+            },
+            Track {
+                duration: 482,
+                track_number: 4,
+                year: 1971,
+                track_source: TrackSource::Source(spotify::CAPABILITIES),
+                ..test_track("Heaven", "Nobody", "Nothing")
+            },
+        ]);
+
+        // A typo in both the title and artist shares nothing with the library, yet is matched
+        let typo = &sources[songs.len() + 1];
+        assert!(library.candidates(typo).is_empty());
+        let result = search(typo.clone(), &library, &config, &MappingStore::default());
+        assert!(result.is_match());
+        assert_eq!(result.track.id, "Stairway to Heaven");
+
+        // The index finds exactly what scoring the whole library finds
+        for source in sources {
+            let indexed = search(source.clone(), &library, &config, &MappingStore::default());
+            let linear = rank(&source, &songs, &config);
+            assert_eq!(
+                indexed.candidates.first().map(|c| (&c.track.id, c.score())),
+                linear.first().map(|c| (&c.track.id, c.score())),
+                "{}",
+                source.title
+            );
+        }
+    }
+
+    #[test]
+    fn test_common_keys() {
+        // Hundreds of songs sharing common words, none of them on an album
+        let songs: Vec<Track> = (0..1500)
+            .map(|i| Track {
+                id: format!("love-{i}"),
+                track_source: TrackSource::Library,
+                ..test_track(&format!("The Love Song of {i}"), &format!("Artist {i}"), "")
+            })
+            .collect();
+        let library = Library::new(songs);
+
+        // Common words don't pull in every song having them when the artist narrows it down
+        let love_song = Track {
+            title: String::from("The Love Song"),
+            artists: vec![String::from("Artist 7")],
+            ..Default::default()
+        };
+        let candidates = library.candidates(&love_song);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].id, "love-7");
+
+        // They're still looked up when a track has nothing else
+        let unknown_artist = Track {
+            artists: vec![String::from("Nobody")],
+            ..love_song
+        };
+        assert!(!library.candidates(&unknown_artist).is_empty());
+
+        // Neither stopwords nor empty albums match every song
+        let unrelated = Track {
+            title: String::from("Of The"),
+            artists: vec![String::from("Nobody")],
+            ..Default::default()
+        };
+        assert!(library.candidates(&unrelated).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::Track;
+
+/// What to do with a source track the user resolved by hand in an earlier import
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mapping {
+    /// Use this subsonic song
+    Song(String),
+    /// Never import this track
+    Skip,
+}
+
+/// A remembered mapping, with enough details about the source track to recognise it when listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingEntry {
+    pub mapping: Mapping,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub artist: String,
+    #[serde(default)]
+    pub isrc: Option<String>,
+}
+
+/// Mappings from source track IDs to subsonic songs, saved as JSON so that tracks resolved by hand
+/// don't have to be resolved again in the next import.
+#[derive(Debug, Default)]
+pub struct MappingStore {
+    path: PathBuf,
+    entries: BTreeMap<String, MappingEntry>,
+}
+
+impl MappingStore {
+    /// The default location of the mapping store, inside the user's data directory
+    pub fn default_path() -> PathBuf {
+        dirs::data_dir()
+            .unwrap_or_default()
+            .join("TuneTracker")
+            .join("mappings.json")
+    }
+
+    /// Load the mappings saved at a path, a missing file is an empty store
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid mappings file {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        Ok(Self { path, entries })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        }
+
+        let contents = serde_json::to_string_pretty(&self.entries).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, contents)
+            .map_err(|e| format!("Failed to write {}: {e}", self.path.display()))
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Find the mapping of a source track, by its ID or otherwise by its ISRC
+    pub fn get(&self, track: &Track) -> Option<&Mapping> {
+        if let Some(entry) = self.entries.get(&track.id) {
+            return Some(&entry.mapping);
+        }
+
+        let isrc = track.isrc.as_ref()?;
+        self.entries
+            .values()
+            .find(|entry| entry.isrc.as_ref() == Some(isrc))
+            .map(|entry| &entry.mapping)
+    }
+
+    /// Remember a mapping for a source track
+    pub fn insert(&mut self, track: &Track, mapping: Mapping) {
+        self.entries.insert(
+            track.id.clone(),
+            MappingEntry {
+                mapping,
+                title: track.title.clone(),
+                artist: track.artist.clone(),
+                isrc: track.isrc.clone(),
+            },
+        );
+    }
+
+    /// Set the mapping of a source track by its ID, keeping the details of an existing entry
+    pub fn set(&mut self, id: &str, mapping: Mapping) {
+        self.entries
+            .entry(id.to_string())
+            .and_modify(|entry| entry.mapping = mapping.clone())
+            .or_insert(MappingEntry {
+                mapping,
+                title: String::new(),
+                artist: String::new(),
+                isrc: None,
+            });
+    }
+
+    /// Whether a source track ID has a mapping
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Forget the mapping of a source track, returns whether there was one
+    pub fn remove(&mut self, id: &str) -> bool {
+        self.entries.remove(id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MappingEntry)> {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::source::CollectionMetadata;
+
+/// Starts the line of a playlist comment holding the marker
+const PREFIX: &str = "TuneTracker:";
+
+/// Records where an imported playlist came from, kept as JSON on the last line of its comment so
+/// a later import can recognise the playlist even without the import history (eg. when run from
+/// another machine).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMarker {
+    /// The source provider the playlist was imported from (eg. "spotify")
+    pub provider: String,
+    /// The collection within the source, as given by `CollectionId`'s display
+    pub playlist: String,
+    /// The version of the collection that was imported, if the source has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+    /// When the playlist was imported, in UTC (eg. "2024-01-31T18:30:00Z")
+    pub imported: String,
+}
+
+impl SourceMarker {
+    /// A marker for importing a collection now
+    pub fn new(provider: &str, metadata: &CollectionMetadata) -> Self {
+        Self {
+            provider: provider.to_string(),
+            playlist: metadata.id.to_string(),
+            snapshot: metadata.snapshot.clone(),
+            imported: timestamp(SystemTime::now()),
+        }
+    }
+
+    /// Find the marker in a playlist comment
+    pub fn parse(comment: &str) -> Option<Self> {
+        let line = comment
+            .lines()
+            .rev()
+            .find_map(|line| line.trim().strip_prefix(PREFIX))?;
+
+        serde_json::from_str(line).ok()
+    }
+
+    /// Whether the marker was left by importing this collection
+    pub fn is_from(&self, provider: &str, playlist: &str) -> bool {
+        self.provider == provider && self.playlist == playlist
+    }
+
+    /// The comment of an imported playlist, the description followed by the marker
+    pub fn comment(&self, description: &str) -> String {
+        let marker = serde_json::to_string(self).unwrap_or_default();
+
+        match description.trim().is_empty() {
+            true => format!("{PREFIX}{marker}"),
+            false => format!("{}\n\n{PREFIX}{marker}", description.trim()),
+        }
+    }
+
+    /// Fill in a description template. Known placeholders are `{description}`, `{name}`,
+    /// `{provider}`, `{playlist}` and `{date}`.
+    pub fn describe(&self, template: &str, metadata: &CollectionMetadata) -> String {
+        let date = self.imported.split('T').next().unwrap_or_default();
+
+        template
+            .replace("{description}", &metadata.description)
+            .replace("{name}", &metadata.name)
+            .replace("{provider}", &self.provider)
+            .replace("{playlist}", &self.playlist)
+            .replace("{date}", date)
+    }
+}
+
+/// Formats a time as an ISO 8601 UTC timestamp
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+    // Converts days since 1970-01-01 into a date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::source::CollectionId;
+
+    #[test]
+    fn test_source_marker() {
+        assert_eq!(
+            timestamp(UNIX_EPOCH + std::time::Duration::from_secs(951_827_696)),
+            "2000-02-29T12:34:56Z"
+        );
+
+        let metadata = CollectionMetadata {
+            id: CollectionId::Playlist(String::from("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M")),
+            name: String::from("Road Trip"),
+            description: String::from("Songs for the road"),
+            owner_id: String::from("ledzeppelinlover67"),
+            owner_name: None,
+            collaborative: false,
+            snapshot: Some(String::from("AAAAB")),
+            total: 42,
+        };
+        let marker = SourceMarker {
+            imported: String::from("2024-01-31T18:30:00Z"),
+            ..SourceMarker::new("spotify", &metadata)
+        };
+
+        let description = marker.describe("{description} ({provider}, {date})", &metadata);
+        assert_eq!(description, "Songs for the road (spotify, 2024-01-31)");
+
+        // The marker survives being read back from the comment, whatever the description says
+        let comment = marker.comment(&description);
+        assert!(comment.starts_with("Songs for the road (spotify, 2024-01-31)\n\nTuneTracker:{"));
+        assert_eq!(SourceMarker::parse(&comment), Some(marker.clone()));
+        assert_eq!(
+            SourceMarker::parse(&marker.comment("")),
+            Some(marker.clone())
+        );
+        assert!(marker.is_from("spotify", "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"));
+        assert!(!marker.is_from("csv", "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"));
+
+        assert_eq!(SourceMarker::parse("Songs for the road"), None);
+        assert_eq!(SourceMarker::parse("TuneTracker:{not json"), None);
+    }
+}
@@ -1,137 +1,492 @@
+pub mod cache;
+pub mod config;
+pub mod csv_import;
+pub mod destination;
+pub mod export;
+pub mod library;
+pub mod mappings;
+pub mod marker;
+pub mod normalize;
+pub mod playlist_file;
+pub mod similarity;
+pub mod source;
 pub mod spotify;
 pub mod subsonic;
+pub mod sync;
+pub mod version;
 
+use std::sync::LazyLock;
+
+use regex::Regex;
 use rspotify_model::FullTrack;
+use serde::{Deserialize, Serialize};
 use submarine::data::Child;
 
-#[derive(Debug, PartialEq, Eq, Clone, Default)]
+use config::MatchConfig;
+use library::Library;
+use mappings::{Mapping, MappingStore};
+use normalize::normalize;
+use similarity::similarity;
+use source::Capabilities;
+use version::ParsedTitle;
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
 pub struct Track {
     pub title: String,
+    /// The artist credit as displayed (eg. "Artist A feat. Artist B")
     pub artist: String,
+    /// Every individual artist credited on the track
+    pub artists: Vec<String>,
     pub album: String,
     pub duration: i32,
     pub track_number: u32,
     pub disc_number: u32,
     pub year: i32,
+    /// The year the track was first released, when it's known to be earlier than `year`
+    /// (eg. the tagged year of a remaster)
+    pub original_year: Option<i32>,
     pub id: String,
     pub isrc: Option<String>,
     pub musicbrainz_id: Option<String>,
+    /// File format of the track on the server (eg. "flac"), if known
+    pub format: Option<String>,
+    /// Where the file of the track is stored on the server, relative to its music folder
+    pub path: Option<String>,
     pub track_source: TrackSource,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Default)]
+/// Where a track came from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum TrackSource {
-    Subsonic,
-    #[default]
-    Spotify,
+    /// A song in the library tracks are matched against
+    Library,
+    /// A track to import, along with what can be relied on in the source it came from
+    Source(Capabilities),
 }
 
-#[derive(Debug)]
-#[allow(unused)]
-struct Match {
-    title: bool,
-    artist: bool,
-    album: bool,
-    duration: bool,
-    track_number: bool,
-    multi_disc_album: bool,
-    year: bool,
+impl Default for TrackSource {
+    fn default() -> Self {
+        TrackSource::Source(Capabilities::default())
+    }
+}
+
+/// Candidates scoring within this many points of the best match are considered ambiguous
+pub const NEAR_TIE_MARGIN: i32 = 5;
+
+/// How many of the best scoring candidates are kept for each search
+pub const MAX_CANDIDATES: usize = 10;
+
+/// How well a target track matched a source track, with the points each criterion contributed.
+/// Criteria that didn't match contribute nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Match {
+    pub title: i32,
+    pub artist: i32,
+    pub album: i32,
+    pub isrc: i32,
+    pub duration: i32,
+    pub track_number: i32,
+    pub year: i32,
+    /// Penalty for conflicting version qualifiers (eg. Live vs studio), zero or negative
+    pub version: i32,
+}
+
+impl Match {
+    pub fn score(&self) -> i32 {
+        self.title
+            + self.artist
+            + self.album
+            + self.isrc
+            + self.duration
+            + self.track_number
+            + self.year
+            + self.version
+    }
+}
+
+impl std::fmt::Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "title +{}, artist +{}, album +{}, isrc +{}, duration +{}, track number +{}, year +{}",
+            self.title,
+            self.artist,
+            self.album,
+            self.isrc,
+            self.duration,
+            self.track_number,
+            self.year
+        )?;
+
+        if self.version != 0 {
+            write!(f, ", version {}", self.version)?;
+        }
+
+        write!(f, " = {}", self.score())
+    }
 }
 
-#[allow(unused)]
 impl Track {
-    // Compares different aspects of two songs and gives a rating based on how well they match
-    // A song is considered a match if the rating is 70 or higher
-    pub fn match_tracks(source: &Self, target: &Self) -> bool {
-        let mut score = 0;
+    // Compares different aspects of two songs and rates how well they match, criterion by criterion
+    // A song is considered a match if the total score reaches the configured threshold
+    pub fn match_tracks(source: &Self, target: &Self, config: &MatchConfig) -> Match {
+        let mut matched = Match::default();
 
         // 1. Album name matching
-        let album_name_match = Self::string_comparisons(&source.album, &target.album);
-        score += album_name_match;
+        let album_similarity = Self::string_similarity(&source.album, &target.album);
+        matched.album = Self::points(album_similarity, config.album_weight);
 
         // 2. International Standard Recording Code matching
-        if let (Some(source_isrc), Some(target_isrc)) = (&source.isrc, &target.isrc) {
-            if source_isrc == target_isrc {
-
-                // Compilation (Greatest Hits, etc) albums may share the same ISRC for certain tracks
-                // By checking if the album name is even slightly a match, this will eliminate most false positives
-                if album_name_match > 0 {
-                    return true
-                }
+        // The other criteria are still scored so that several tracks sharing an ISRC are ranked sensibly
+        if let (Some(source_isrc), Some(target_isrc)) = (&source.isrc, &target.isrc)
+            && source_isrc == target_isrc
+        {
+            // Compilation (Greatest Hits, etc) albums may share the same ISRC for certain tracks
+            // By checking if the album name is even slightly a match, this will eliminate most false positives
+            if album_similarity > 0.0 {
+                matched.isrc = config.isrc_weight;
             }
         }
 
         // 3. Song name matching
-        score += Self::string_comparisons(&source.title, &target.title);
+        // Titles are compared without their version qualifiers (eg. " - Remaster", "(Live)"),
+        // instead conflicting qualifiers are penalized while cosmetic ones like remasters are ignored
+        let source_title = ParsedTitle::parse(&source.title);
+        let target_title = ParsedTitle::parse(&target.title);
+        matched.title =
+            Self::string_comparisons(&source_title.base, &target_title.base, config.title_weight);
+        matched.version =
+            -config.qualifier_conflict_penalty * source_title.conflicts(&target_title) as i32;
 
         // 4. Year matching
-        if source.year == target.year {
-            score += 10
+        // Remasters are often tagged with the year of the remaster instead of the year the track
+        // was first released, so a match on either year counts
+        if source
+            .known_years()
+            .any(|year| target.known_years().any(|other| other == year))
+        {
+            matched.year = config.year_weight
         }
 
         // 5. Artist name matching
-        if source.artist.to_lowercase() == target.artist.to_lowercase() {
-            score += 20
-        }
+        matched.artist =
+            Self::artist_comparisons(&source.artists, &target.artists, config.artist_weight);
 
-        // 6. Account for 1-3 seconds of variation in track duration
-        if ((source.duration - target.duration).abs()) <= 3 {
+        // 6. Account for a few seconds of variation in track duration
+        // Unknown durations (0) never match, data exports don't include durations
+        if source.duration > 0
+            && ((source.duration - target.duration).abs()) <= config.duration_tolerance
+        {
             // If the duration is an exact match, rate it higher
             if source.duration == target.duration {
-                score += 20
+                matched.duration = config.duration_exact_weight
             } else {
-                score += 10;
+                matched.duration = config.duration_near_weight;
             }
         }
 
         // 7. Track number
-        // Spotify resets the track number for each disc, meaning the track number
-        // is unreliable unless it's not a multi-disc album
-        if source.track_source == TrackSource::Spotify
-            && !source.disc_number > 1
-            && source.track_number == target.track_number
-        {
-            score += 20
+        if source.scores_track_number() && source.track_number == target.track_number {
+            matched.track_number = config.track_number_weight
         }
 
-        score >= 70
+        matched
     }
 
-    // Compare two strings and return a rating on how similar they are.
-    fn string_comparisons(string1: &str, string2: &str) -> i32 {
-        let source = string1.to_lowercase();
-        let target = string2.to_lowercase();
+    // The thresholds this track has to reach, scaled down to the details its source knows.
+    // Tracks without a duration, year or track number (eg. from a data export) can only match on
+    // their title, artist and album, so the thresholds are lowered by the same proportion. A track
+    // number that's known but not scored (see scores_track_number) doesn't lower them.
+    fn thresholds(&self, config: &MatchConfig) -> (i32, i32) {
+        let full = config.title_weight
+            + config.artist_weight
+            + config.album_weight
+            + config.year_weight
+            + config.duration_exact_weight
+            + config.track_number_weight;
 
-        // An exact match should be rated highest
-        if source == target {
-            return 20;
+        let mut unknown = 0;
+        if self.known_years().next().is_none() {
+            unknown += config.year_weight;
+        }
+        if self.duration == 0 {
+            unknown += config.duration_exact_weight;
+        }
+        if self.track_number == 0 {
+            unknown += config.track_number_weight;
         }
 
-        // If either source or target contain a prefix/suffix (eg. (Remaster))
-        if source.contains(&target) || target.contains(&source) {
-            return 10;
+        if unknown == 0 || full <= 0 {
+            return (config.threshold, config.confirm_threshold);
         }
 
-        // No match was found
-        0
+        let scale = |threshold: i32| threshold * (full - unknown) / full;
+        (scale(config.threshold), scale(config.confirm_threshold))
     }
+
+    // Whether this track's number can identify it. Some sources (eg. spotify) reset the track
+    // number for each disc, meaning the track number is unreliable unless it's on the first disc
+    fn scores_track_number(&self) -> bool {
+        let per_disc = match self.track_source {
+            TrackSource::Source(capabilities) => capabilities.per_disc_track_numbers,
+            TrackSource::Library => false,
+        };
+
+        self.track_number > 0 && (!per_disc || self.disc_number <= 1)
+    }
+
+    // Every year this track is known by. Unknown years (0) are left out
+    fn known_years(&self) -> impl Iterator<Item = i32> {
+        [Some(self.year), self.original_year]
+            .into_iter()
+            .flatten()
+            .filter(|year| *year > 0)
+    }
+
+    // Compare two strings and return a rating on how similar they are, out of the given weight.
+    // Only an exact match gets the full weight, typos, reordered words and extra words
+    // (eg. (Remaster)) are rated proportionally lower.
+    fn string_comparisons(string1: &str, string2: &str, weight: i32) -> i32 {
+        Self::points(Self::string_similarity(string1, string2), weight)
+    }
+
+    // Both strings are normalized first so punctuation, accents and casing don't matter.
+    fn string_similarity(string1: &str, string2: &str) -> f64 {
+        let source = normalize(string1);
+        let target = normalize(string2);
+
+        similarity(&source, &target)
+    }
+
+    fn points(similarity: f64, weight: i32) -> i32 {
+        (similarity * weight as f64).floor() as i32
+    }
+
+    // Compare two lists of artists and return a rating on how much they overlap, out of the given weight.
+    // Each artist of the shorter list is compared against its best match in the longer list,
+    // so a "feat." credit that's missing on one side doesn't count against the match.
+    fn artist_comparisons(source: &[String], target: &[String], weight: i32) -> i32 {
+        let (fewer, more) = match source.len() <= target.len() {
+            true => (source, target),
+            false => (target, source),
+        };
+
+        if fewer.is_empty() {
+            return 0;
+        }
+
+        let total: i32 = fewer
+            .iter()
+            .map(|artist| {
+                more.iter()
+                    .map(|other| Self::string_comparisons(artist, other, weight))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .sum();
+
+        total / fewer.len() as i32
+    }
+}
+
+/// Separates the artists of a credit: ";", " / ", "feat.", "ft." and "featuring" in any casing
+/// and with or without a dot, and a lowercase " x " for collaborations. A capital X is left
+/// alone since it's often part of a name.
+static ARTIST_SEPARATOR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i);|\s/\s|\s(?:feat\.?|ft\.?|featuring|(?-i:x))\s").unwrap());
+
+// Splits an artist credit like "Artist A; Artist B" or "Artist A feat. Artist B" into the
+// individual artists. "&" and "," are left alone since they're often part of a band name.
+fn split_artists(credit: &str) -> Vec<String> {
+    ARTIST_SEPARATOR
+        .split(credit)
+        .map(|artist| artist.trim().to_string())
+        .filter(|artist| !artist.is_empty())
+        .collect()
 }
 
-// Takes a single source track and a slice of target tracks and compares the source against
-// each item of the slice. Returning the first track with the highest match, or the source
-// track if no match is found.
+/// A library track together with how well it scored against a source track
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub track: &'a Track,
+    pub breakdown: Match,
+}
+
+impl Candidate<'_> {
+    pub fn score(&self) -> i32 {
+        self.breakdown.score()
+    }
+}
+
+/// How confident a search is in its best candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    /// The best candidate reached the threshold and is matched without asking
+    Accept,
+    /// The best candidate reached the confirm threshold, the user should confirm it's right
+    Confirm,
+    /// No candidate scored high enough to even be suggested
+    Reject,
+    /// The user chose to always skip this track in an earlier import
+    Ignored,
+}
+
+/// The outcome of searching the library for a single source track
+#[derive(Debug, Clone)]
+pub struct SearchResult<'a> {
+    /// The track that was searched for
+    pub source: Track,
+    /// The best matching library track, or the source track if nothing scored high enough
+    pub track: Track,
+    /// The best scoring candidates, highest score first
+    pub candidates: Vec<Candidate<'a>>,
+    pub confidence: Confidence,
+}
+
+impl SearchResult<'_> {
+    pub fn is_match(&self) -> bool {
+        self.confidence == Confidence::Accept
+    }
+
+    /// The best candidate, if it scored high enough to ask the user whether it's right
+    pub fn suggestion(&self) -> Option<&Candidate<'_>> {
+        match self.confidence {
+            Confidence::Confirm => self.candidates.first(),
+            _ => None,
+        }
+    }
+
+    /// Every candidate scoring within NEAR_TIE_MARGIN of the best match, including the best match itself
+    pub fn contenders(&self) -> &[Candidate<'_>] {
+        if !self.is_match() {
+            return &[];
+        }
+
+        let best = self.candidates[0].score();
+        let end = self
+            .candidates
+            .iter()
+            .position(|c| best - c.score() > NEAR_TIE_MARGIN)
+            .unwrap_or(self.candidates.len());
+
+        &self.candidates[..end]
+    }
+
+    /// Whether another candidate scored too close to the chosen match to be sure it's the right one
+    pub fn is_ambiguous(&self) -> bool {
+        self.contenders().len() > 1
+    }
+}
+
+// Scores every track in the collection against the source track and returns the best
+// candidates, highest score first. Candidates with equal scores keep their library order.
+pub fn rank<'a>(
+    source_track: &Track,
+    collection: impl IntoIterator<Item = &'a Track>,
+    config: &MatchConfig,
+) -> Vec<Candidate<'a>> {
+    let mut candidates: Vec<Candidate> = collection
+        .into_iter()
+        .map(|track| Candidate {
+            track,
+            breakdown: Track::match_tracks(source_track, track, config),
+        })
+        .filter(|c| c.score() > 0)
+        .collect();
+
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.score()));
+    candidates.truncate(MAX_CANDIDATES);
+    candidates
+}
+
+// Takes a single source track and the target library and compares the source against every
+// plausible candidate from the library. Returning the highest scoring track, or the source track
+// if no track scored high enough to be a match.
 //
 // The source playlist must be recreated 1:1 even if the track doesn't match because the subsonic
 // api doesn't support adding tracks at a specific index. so the songs must be added all at once
 // and iterated through, adding the matches and prompting for user input for the missing songs
-pub fn search(source_track: Track, collection: &[Track]) -> Track {
-    for target_track in collection {
-        if Track::match_tracks(&source_track, target_track) {
-            return target_track.clone();
+pub fn search<'a>(
+    source_track: Track,
+    library: &'a Library,
+    config: &MatchConfig,
+    mappings: &MappingStore,
+) -> SearchResult<'a> {
+    // Tracks the user resolved by hand before don't need to be scored again. A mapping to a song
+    // that's no longer in the library is ignored so the track can be matched from scratch
+    match mappings.get(&source_track) {
+        Some(Mapping::Song(id)) => {
+            if let Some(track) = library.get(id) {
+                let breakdown = Track::match_tracks(&source_track, track, config);
+                return SearchResult {
+                    track: track.clone(),
+                    source: source_track,
+                    candidates: vec![Candidate { track, breakdown }],
+                    confidence: Confidence::Accept,
+                };
+            }
         }
+        Some(Mapping::Skip) => {
+            return SearchResult {
+                track: source_track.clone(),
+                source: source_track,
+                candidates: Vec::new(),
+                confidence: Confidence::Ignored,
+            };
+        }
+        None => {}
+    }
+
+    let (threshold, confirm_threshold) = source_track.thresholds(config);
+
+    // Tracks that can only match on their title, artist and album reach a lowered threshold with
+    // just their title and artist, so they're only matched without asking when the album matches
+    // too. Otherwise a live or compilation copy of the song would be accepted.
+    let album_matches = |candidate: &Candidate| {
+        threshold >= config.threshold || candidate.breakdown.album * 2 >= config.album_weight
+    };
+
+    let confidence_of = |candidates: &[Candidate]| match candidates.first() {
+        Some(best) if best.score() >= threshold && album_matches(best) => Confidence::Accept,
+        Some(best) if best.score() >= confirm_threshold => Confidence::Confirm,
+        _ => Confidence::Reject,
+    };
+
+    // Only the tracks sharing something with the source track are scored first. A track with a
+    // typo in both its title and artist can share nothing and still score high enough on the rest,
+    // so unless one of them is matched outright the whole library is scored instead
+    let mut candidates = rank(&source_track, library.candidates(&source_track), config);
+    if confidence_of(&candidates) != Confidence::Accept {
+        candidates = rank(&source_track, library.tracks(), config);
+    }
+    let confidence = confidence_of(&candidates);
+
+    let track = match confidence {
+        Confidence::Accept => candidates[0].track.clone(),
+        _ => source_track.clone(),
+    };
+
+    SearchResult {
+        source: source_track,
+        track,
+        candidates,
+        confidence,
     }
+}
+
+// Gets the year out of a spotify release date. Depending on the precision the date is either
+// "1982", "1982-11" or "1982-11-19". Returns 0 if the year is unknown.
+fn release_year(release_date: Option<&str>, precision: Option<&str>) -> i32 {
+    let Some(release_date) = release_date else {
+        return 0;
+    };
+
+    let year = match precision {
+        Some("year") => release_date,
+        _ => release_date.split('-').next().unwrap_or_default(),
+    };
 
-    source_track
+    year.trim().parse().unwrap_or(0)
 }
 
 /// Spotify
@@ -139,27 +494,40 @@ impl TryFrom<FullTrack> for Track {
     type Error = ();
 
     fn try_from(track: FullTrack) -> Result<Self, ()> {
-        // Get release year
-        let release_year: i32 = match track.album.release_date {
-            Some(release_date) => match release_date.split_once('-') {
-                Some((year, _)) => year.parse().unwrap_or(0),
-                _ => Default::default(),
-            },
-            _ => 0,
-        };
+        if track.artists.is_empty() {
+            return Err(());
+        }
+
+        let release_year = release_year(
+            track.album.release_date.as_deref(),
+            track.album.release_date_precision.as_deref(),
+        );
 
         Ok(Self {
             title: track.name,
-            artist: track.artists.first().ok_or(())?.name.to_string(),
+            artist: track
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", "),
+            artists: track
+                .artists
+                .into_iter()
+                .map(|artist| artist.name)
+                .collect(),
             album: track.album.name,
             duration: track.duration.as_seconds_f64() as i32,
             track_number: track.track_number,
             disc_number: track.disc_number as u32,
             year: release_year,
+            original_year: None,
             id: track.id.ok_or(())?.to_string(),
             isrc: track.external_ids.get("isrc").cloned(),
             musicbrainz_id: None,
-            track_source: TrackSource::Spotify,
+            format: None,
+            path: None,
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
         })
     }
 }
@@ -169,22 +537,51 @@ impl TryFrom<Child> for Track {
     type Error = ();
 
     fn try_from(track: Child) -> Result<Self, ()> {
+        let artist = track.artist.ok_or(())?;
+
+        // OpenSubsonic servers list every artist, plain subsonic only has the credit
+        let artists = match track.artists.is_empty() {
+            false => track
+                .artists
+                .into_iter()
+                .map(|artist| artist.name)
+                .collect(),
+            true => split_artists(&artist),
+        };
+
         Ok(Self {
             title: track.title,
-            artist: track.artist.ok_or(())?,
+            artist,
+            artists,
             album: track.album.ok_or(())?,
             duration: track.duration.ok_or(())?,
             track_number: track.track.ok_or(())? as u32,
             disc_number: track.disc_number.unwrap_or(0) as u32,
             year: track.year.ok_or(())?,
+            // Only known from the album, see subsonic::fetch_subsonic_songs
+            original_year: None,
             id: track.id,
             isrc: track.isrc.first().cloned(),
             musicbrainz_id: track.music_brainz_id,
-            track_source: TrackSource::Subsonic,
+            format: track.suffix,
+            path: track.path,
+            track_source: TrackSource::Library,
         })
     }
 }
 
+/// A track with only its title, artist and album known, to build test fixtures from
+#[cfg(test)]
+pub(crate) fn test_track(title: &str, artist: &str, album: &str) -> Track {
+    Track {
+        title: String::from(title),
+        artist: String::from(artist),
+        artists: vec![String::from(artist)],
+        album: String::from(album),
+        ..Default::default()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -192,86 +589,87 @@ mod tests {
     #[test]
     fn test_matching() {
         // Using example songs from spotify
+        let spotify = TrackSource::Source(spotify::CAPABILITIES);
         let spotify_songs = vec![
             Track {
-                title: String::from("St. Tristan's Sword - Rough Mix"),
-                artist: String::from("Led Zeppelin"),
-                album: String::from("Coda (Deluxe Edition)"),
                 duration: 341,
                 track_number: 3,
                 disc_number: 3,
                 year: 1982,
                 id: String::from("xxx"),
                 isrc: Some(String::from("USAT21500101")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track(
+                    "St. Tristan's Sword - Rough Mix",
+                    "Led Zeppelin",
+                    "Coda (Deluxe Edition)",
+                )
             },
             Track {
-                title: String::from("The Court Of The Crimson King"),
-                artist: String::from("King Crimson"),
-                album: String::from(
-                    "In The Court Of The Crimson King (Expanded & Remastered Original Album Mix)",
-                ),
                 duration: 602,
                 track_number: 5,
                 disc_number: 1,
                 year: 1969,
                 id: String::from("xxx"),
                 isrc: Some(String::from("GBCTX1400804")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track(
+                    "The Court Of The Crimson King",
+                    "King Crimson",
+                    "In The Court Of The Crimson King (Expanded & Remastered Original Album Mix)",
+                )
             },
             Track {
-                title: String::from("Pictures Of A City"),
-                artist: String::from("King Crimson"),
-                album: String::from("In The Wake Of Poseidon"),
                 duration: 481,
                 track_number: 2,
                 disc_number: 1,
                 year: 1970,
                 id: String::from("xxx"),
                 isrc: Some(String::from("GBCTX1500265")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track(
+                    "Pictures Of A City",
+                    "King Crimson",
+                    "In The Wake Of Poseidon",
+                )
             },
             Track {
-                title: String::from("The Wanton Song - Remaster"),
-                artist: String::from("Led Zeppelin"),
-                album: String::from("Physical Graffiti (Remaster)"),
                 duration: 248,
                 track_number: 6,
                 disc_number: 2,
                 year: 1975,
                 id: String::from("xxx"),
                 isrc: Some(String::from("USAT21300975")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track(
+                    "The Wanton Song - Remaster",
+                    "Led Zeppelin",
+                    "Physical Graffiti (Remaster)",
+                )
             },
             Track {
-                title: String::from("The Sky Is Fallin'"),
-                artist: String::from("Queens of the Stone Age"),
-                album: String::from("Songs For The Deaf"),
                 duration: 376,
                 track_number: 5,
                 disc_number: 1,
                 year: 2002,
                 id: String::from("xxx"),
                 isrc: Some(String::from("USIR10211296")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track(
+                    "The Sky Is Fallin'",
+                    "Queens of the Stone Age",
+                    "Songs For The Deaf",
+                )
             },
             Track {
-                title: String::from("Street Spirit (Fade Out)"),
-                artist: String::from("Radiohead"),
-                album: String::from("The Bends"),
                 duration: 253,
                 track_number: 12,
                 disc_number: 1,
                 year: 1995,
                 id: String::from("xxx"),
                 isrc: Some(String::from("GBAYE9400061")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Spotify,
+                track_source: spotify,
+                ..test_track("Street Spirit (Fade Out)", "Radiohead", "The Bends")
             },
         ];
 
@@ -279,37 +677,37 @@ mod tests {
         // Tagged automatically using beets autotagger. https://github.com/beetbox/beets
         let subsonic_songs = vec![
             Track {
-                title: String::from("The Sky Is Fallin'"),
-                artist: String::from("Queens of the Stone Age"),
-                album: String::from("Songs For The Deaf"),
                 duration: 375,
                 track_number: 6,
                 disc_number: 1,
                 year: 2002,
                 id: String::from("xxx"),
                 isrc: Some(String::from("USIR10211296")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track(
+                    "The Sky Is Fallin'",
+                    "Queens of the Stone Age",
+                    "Songs For The Deaf",
+                )
             },
             Track {
-                title: String::from("Pictures of a City (including 42nd at Treadmill)"),
-                artist: String::from("King Crimson"),
-                album: String::from("In the Wake of Poseidon"),
                 duration: 482,
                 track_number: 2,
                 disc_number: 1,
                 // This albums release date is incorrect. Subsonic/navidrome only returns the year
                 // the song was remastered, not the year it was originally released.
                 year: 2011,
+                original_year: Some(1970),
                 id: String::from("xxx"),
                 isrc: Some(String::from("GBCTX9900221")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track(
+                    "Pictures of a City (including 42nd at Treadmill)",
+                    "King Crimson",
+                    "In the Wake of Poseidon",
+                )
             },
             Track {
-                title: String::from("The Wanton Song"),
-                artist: String::from("Led Zeppelin"),
-                album: String::from("Physical Graffiti"),
                 duration: 249,
                 track_number: 12,
                 disc_number: 2,
@@ -317,56 +715,538 @@ mod tests {
                 year: 1995,
                 id: String::from("xxx"),
                 isrc: Some(String::from("USAT21300975")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track("The Wanton Song", "Led Zeppelin", "Physical Graffiti")
             },
             Track {
-                title: String::from("Street Spirit"),
-                artist: String::from("Radiohead"),
-                album: String::from("The Bends"),
                 duration: 254,
                 track_number: 12,
                 disc_number: 1,
                 year: 1994,
                 id: String::from("xxx"),
                 isrc: Some(String::from("GBAYE9400061")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track("Street Spirit", "Radiohead", "The Bends")
             },
             Track {
-                title: String::from("The Court of the Crimson King"),
-                artist: String::from("King Crimson"),
-                album: String::from("In the Court of the Crimson King"),
                 duration: 567,
                 track_number: 5,
                 disc_number: 1,
                 year: 2019,
                 id: String::from("xxx"),
                 isrc: Some(String::from("B07X13ZHG9")),
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track(
+                    "The Court of the Crimson King",
+                    "King Crimson",
+                    "In the Court of the Crimson King",
+                )
             },
             Track {
-                title: String::from("St. Tristan’s Sword (rough mix)"),
-                artist: String::from("Led Zeppelin"),
-                album: String::from("Coda"),
                 duration: 341,
                 track_number: 19,
                 disc_number: 3,
                 year: 2015,
                 id: String::from("xxx"),
-                isrc: None,
-                musicbrainz_id: None,
-                track_source: TrackSource::Subsonic,
+                track_source: TrackSource::Library,
+                ..test_track("St. Tristan’s Sword (rough mix)", "Led Zeppelin", "Coda")
             },
         ];
 
+        let library = Library::new(subsonic_songs.clone());
+        let config = MatchConfig::default();
+
+        // The index must pick exactly the same tracks as scoring the whole library
+        for song in &spotify_songs {
+            let indexed = search(song.clone(), &library, &config, &MappingStore::default());
+            let linear = rank(song, &subsonic_songs, &config);
+            assert_eq!(
+                indexed.candidates.first().map(|c| (&c.track.id, c.score())),
+                linear.first().map(|c| (&c.track.id, c.score()))
+            );
+        }
+
         let matches: Vec<Track> = spotify_songs
             .into_iter()
-            .map(|t| search(t, &subsonic_songs))
-            .filter(|t| t.track_source == TrackSource::Subsonic)
+            .map(|t| search(t, &library, &config, &MappingStore::default()).track)
+            .filter(|t| t.track_source == TrackSource::Library)
             .collect();
 
         assert_eq!(matches.len(), 6)
     }
+
+    #[test]
+    fn test_best_candidate() {
+        let spotify_song = Track {
+            duration: 334,
+            track_number: 1,
+            disc_number: 1,
+            year: 1969,
+            id: String::from("xxx"),
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..test_track(
+                "Whole Lotta Love - Remaster",
+                "Led Zeppelin",
+                "Led Zeppelin II (Remaster)",
+            )
+        };
+
+        // An edited version that only just reaches the threshold is listed first
+        let edit = Track {
+            duration: 290,
+            track_number: 1,
+            disc_number: 1,
+            year: 1969,
+            id: String::from("edit"),
+            track_source: TrackSource::Library,
+            ..test_track("Whole Lotta Love", "Led Zeppelin", "Led Zeppelin II")
+        };
+        let studio = Track {
+            id: String::from("studio"),
+            duration: 334,
+            ..edit.clone()
+        };
+
+        let library = Library::new(vec![edit.clone(), studio.clone()]);
+        let result = search(
+            spotify_song.clone(),
+            &library,
+            &MatchConfig::default(),
+            &MappingStore::default(),
+        );
+        assert_eq!(result.track.id, "studio");
+        assert_eq!(result.candidates.len(), 2);
+        assert!(result.candidates[0].score() > result.candidates[1].score());
+        assert!(!result.is_ambiguous());
+
+        // Two copies of the same track can't be told apart and should be flagged
+        let copy = Track {
+            id: String::from("copy"),
+            ..studio.clone()
+        };
+        let library = Library::new(vec![edit, studio, copy]);
+        let result = search(
+            spotify_song,
+            &library,
+            &MatchConfig::default(),
+            &MappingStore::default(),
+        );
+        assert_eq!(result.track.id, "studio");
+        assert_eq!(result.contenders().len(), 2);
+        assert!(result.is_ambiguous());
+    }
+
+    #[test]
+    fn test_manual_mappings() {
+        let spotify_song = Track {
+            duration: 273,
+            track_number: 7,
+            disc_number: 1,
+            year: 1969,
+            id: String::from("spotify:track:xxx"),
+            isrc: Some(String::from("USAT29900609")),
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..test_track(
+                "Ramble On - Remaster",
+                "Led Zeppelin",
+                "Led Zeppelin II (Remaster)",
+            )
+        };
+
+        // A bootleg that would never be matched on its own
+        let bootleg = Track {
+            duration: 301,
+            track_number: 2,
+            disc_number: 1,
+            id: String::from("bootleg"),
+            track_source: TrackSource::Library,
+            ..test_track("Ramble On (Rehearsal)", "Led Zeppelin", "Studio Daze")
+        };
+        let library = Library::new(vec![bootleg]);
+        let config = MatchConfig::default();
+
+        let path = std::env::temp_dir().join(format!("mappings-{}.json", std::process::id()));
+        let mut mappings = MappingStore::load(path.clone()).unwrap();
+        assert!(!search(spotify_song.clone(), &library, &config, &mappings).is_match());
+
+        mappings.insert(&spotify_song, Mapping::Song(String::from("bootleg")));
+        mappings.save().unwrap();
+        let mappings = MappingStore::load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let result = search(spotify_song.clone(), &library, &config, &mappings);
+        assert!(result.is_match());
+        assert_eq!(result.track.id, "bootleg");
+
+        // Another release of the same recording is found by its ISRC
+        let other_release = Track {
+            id: String::from("spotify:track:yyy"),
+            ..spotify_song.clone()
+        };
+        assert!(search(other_release, &library, &config, &mappings).is_match());
+
+        // Mappings to songs that were removed from the library are ignored
+        let mut mappings = MappingStore::default();
+        mappings.insert(&spotify_song, Mapping::Song(String::from("deleted")));
+        let result = search(spotify_song.clone(), &library, &config, &mappings);
+        assert_eq!(result.confidence, Confidence::Reject);
+
+        mappings.set(&spotify_song.id, Mapping::Skip);
+        let result = search(spotify_song.clone(), &library, &config, &mappings);
+        assert_eq!(result.confidence, Confidence::Ignored);
+        assert!(result.candidates.is_empty());
+
+        assert!(mappings.remove(&spotify_song.id));
+        assert!(!mappings.remove(&spotify_song.id));
+    }
+
+    #[test]
+    fn test_data_export() {
+        // Tracks from a data export only have a title, artist, album and URI
+        let track = Track {
+            id: String::from("spotify:track:xxx"),
+            ..test_track(
+                "Ramble On - Remaster",
+                "Led Zeppelin",
+                "Led Zeppelin II (Remaster)",
+            )
+        };
+
+        // Without a duration, year or track number the title, artist and album are enough to match,
+        // even against untagged tracks with no track number or duration
+        let subsonic_song = Track {
+            disc_number: 1,
+            year: 1969,
+            id: String::from("yyy"),
+            track_source: TrackSource::Library,
+            ..test_track("Ramble On (Remaster)", "Led Zeppelin", "Led Zeppelin II")
+        };
+        let breakdown = Track::match_tracks(&track, &subsonic_song, &MatchConfig::default());
+        assert_eq!(breakdown.duration, 0);
+        assert_eq!(breakdown.track_number, 0);
+
+        let library = Library::new(vec![subsonic_song.clone()]);
+        let result = search(
+            track.clone(),
+            &library,
+            &MatchConfig::default(),
+            &MappingStore::default(),
+        );
+        assert!(result.is_match());
+        assert_eq!(result.track.id, "yyy");
+
+        // The title and artist alone reach the lowered threshold, but a copy on another album
+        // (eg. a live album or a compilation) is only suggested
+        let other_album = Track {
+            album: String::from("The Complete BBC Sessions"),
+            id: String::from("zzz"),
+            ..subsonic_song
+        };
+        let library = Library::new(vec![other_album]);
+        let result = search(
+            track.clone(),
+            &library,
+            &MatchConfig::default(),
+            &MappingStore::default(),
+        );
+        assert!(result.candidates[0].score() >= track.thresholds(&MatchConfig::default()).0);
+        assert_eq!(result.confidence, Confidence::Confirm);
+        assert_eq!(result.suggestion().unwrap().track.id, "zzz");
+    }
+
+    #[test]
+    fn test_match_config() {
+        // Ignoring a remastered year changes the score, but not the outcome
+        let spotify_song = Track {
+            duration: 248,
+            track_number: 6,
+            disc_number: 2,
+            year: 1975,
+            id: String::from("xxx"),
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..test_track(
+                "The Wanton Song - Remaster",
+                "Led Zeppelin",
+                "Physical Graffiti (Remaster)",
+            )
+        };
+        let subsonic_song = Track {
+            title: String::from("The Wanton Song"),
+            album: String::from("Physical Graffiti"),
+            duration: 249,
+            track_number: 12,
+            track_source: TrackSource::Library,
+            ..spotify_song.clone()
+        };
+
+        let config = MatchConfig {
+            year_weight: 0,
+            ..Default::default()
+        };
+        let default_score =
+            Track::match_tracks(&spotify_song, &subsonic_song, &MatchConfig::default());
+        let no_year_score = Track::match_tracks(&spotify_song, &subsonic_song, &config);
+        assert_eq!(default_score.year, 10);
+        assert_eq!(no_year_score.year, 0);
+        assert_eq!(default_score.score() - no_year_score.score(), 10);
+        assert_eq!(
+            default_score.to_string(),
+            "title +20, artist +20, album +18, isrc +0, duration +10, track number +0, year +10 = 78"
+        );
+    }
+
+    #[test]
+    fn test_release_years() {
+        assert_eq!(release_year(Some("1982"), Some("year")), 1982);
+        assert_eq!(release_year(Some("1982-11"), Some("month")), 1982);
+        assert_eq!(release_year(Some("1982-11-19"), Some("day")), 1982);
+        assert_eq!(release_year(Some("1982"), None), 1982);
+        assert_eq!(release_year(None, None), 0);
+
+        // A remaster tagged 2011 of an album originally released in 1970
+        let remaster = Track {
+            year: 2011,
+            original_year: Some(1970),
+            ..Default::default()
+        };
+
+        let original = Track {
+            year: 1970,
+            ..Default::default()
+        };
+        let config = MatchConfig::default();
+        assert_eq!(Track::match_tracks(&original, &remaster, &config).year, 10);
+        assert_eq!(Track::match_tracks(&remaster, &original, &config).year, 10);
+
+        // Unknown years never match
+        let unknown = Track::default();
+        assert_eq!(Track::match_tracks(&unknown, &unknown, &config).year, 0);
+    }
+
+    #[test]
+    fn test_multi_disc_track_numbers() {
+        let config = MatchConfig::default();
+        let library_song = Track {
+            track_number: 3,
+            disc_number: 1,
+            track_source: TrackSource::Library,
+            ..Default::default()
+        };
+
+        // Spotify restarts track numbers on every disc, track 3 of disc 2 isn't track 3 of disc 1
+        let second_disc = Track {
+            duration: 200,
+            year: 1975,
+            track_number: 3,
+            disc_number: 2,
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..Default::default()
+        };
+        let first_disc = Track {
+            disc_number: 1,
+            ..second_disc.clone()
+        };
+        assert_eq!(
+            Track::match_tracks(&second_disc, &library_song, &config).track_number,
+            0
+        );
+        assert_eq!(
+            Track::match_tracks(&first_disc, &library_song, &config).track_number,
+            20
+        );
+
+        // The track number of a second disc is known, it just can't score, so the thresholds
+        // stay the same as for any other spotify track
+        assert_eq!(
+            second_disc.thresholds(&config),
+            first_disc.thresholds(&config)
+        );
+        assert_eq!(
+            second_disc.thresholds(&config),
+            (config.threshold, config.confirm_threshold)
+        );
+
+        // Sources that number tracks across the whole album can be trusted on every disc
+        let numbered_across = Track {
+            track_source: TrackSource::Source(Capabilities::default()),
+            ..second_disc.clone()
+        };
+        assert_eq!(
+            Track::match_tracks(&numbered_across, &library_song, &config).track_number,
+            20
+        );
+        assert_eq!(numbered_across.thresholds(&config).0, config.threshold);
+    }
+
+    #[test]
+    fn test_multiple_artists() {
+        assert_eq!(
+            split_artists("David Bowie feat. Queen; Annie Lennox"),
+            vec!["David Bowie", "Queen", "Annie Lennox"]
+        );
+        assert_eq!(
+            split_artists("Simon & Garfunkel"),
+            vec!["Simon & Garfunkel"]
+        );
+        for credit in [
+            "Daft Punk FEAT. Pharrell Williams",
+            "Daft Punk feat Pharrell Williams",
+            "Daft Punk Ft Pharrell Williams",
+            "Daft Punk Featuring Pharrell Williams",
+            "Daft Punk x Pharrell Williams",
+            "Daft Punk / Pharrell Williams",
+        ] {
+            assert_eq!(
+                split_artists(credit),
+                vec!["Daft Punk", "Pharrell Williams"]
+            );
+        }
+        assert_eq!(split_artists("Malcolm X"), vec!["Malcolm X"]);
+        assert_eq!(split_artists("Planet X Band"), vec!["Planet X Band"]);
+        assert_eq!(split_artists("Soft Cell"), vec!["Soft Cell"]);
+
+        let spotify = vec![String::from("Queen"), String::from("David Bowie")];
+        let subsonic = split_artists("Queen feat. David Bowie");
+        assert_eq!(Track::artist_comparisons(&spotify, &subsonic, 20), 20);
+
+        // A featured artist missing on one side still matches the main artist
+        let main_only = vec![String::from("Queen")];
+        assert_eq!(Track::artist_comparisons(&main_only, &spotify, 20), 20);
+
+        // Only half of the credited artists match
+        let other = vec![String::from("Queen"), String::from("Freddie Mercury")];
+        assert_eq!(Track::artist_comparisons(&spotify, &other, 20), 10);
+    }
+
+    #[test]
+    fn test_confidence_tiers() {
+        let spotify_song = Track {
+            duration: 253,
+            track_number: 12,
+            disc_number: 1,
+            year: 1995,
+            id: String::from("xxx"),
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..test_track("Street Spirit (Fade Out)", "Radiohead", "The Bends")
+        };
+
+        // Same title and artist from a different release, only good enough to suggest
+        let subsonic_song = Track {
+            album: String::from("OK Computer OKNOTOK"),
+            duration: 255,
+            track_number: 3,
+            year: 2017,
+            id: String::from("suggested"),
+            track_source: TrackSource::Library,
+            ..spotify_song.clone()
+        };
+
+        let library = Library::new(vec![subsonic_song]);
+        let config = MatchConfig::default();
+
+        let result = search(
+            spotify_song.clone(),
+            &library,
+            &config,
+            &MappingStore::default(),
+        );
+        assert_eq!(result.confidence, Confidence::Confirm);
+        assert!(!result.is_match());
+        assert_eq!(
+            result.suggestion().map(|c| c.track.id.as_str()),
+            Some("suggested")
+        );
+        assert_eq!(result.track, spotify_song);
+
+        // Lowering the threshold accepts it outright
+        let config = MatchConfig {
+            threshold: 40,
+            ..Default::default()
+        };
+        let result = search(
+            spotify_song.clone(),
+            &library,
+            &config,
+            &MappingStore::default(),
+        );
+        assert_eq!(result.confidence, Confidence::Accept);
+        assert_eq!(result.track.id, "suggested");
+
+        // Raising the confirm threshold rejects it
+        let config = MatchConfig {
+            confirm_threshold: 70,
+            ..Default::default()
+        };
+        let result = search(spotify_song, &library, &config, &MappingStore::default());
+        assert_eq!(result.confidence, Confidence::Reject);
+        assert!(result.suggestion().is_none());
+    }
+
+    #[test]
+    fn test_live_version_penalized() {
+        let spotify_song = Track {
+            duration: 295,
+            track_number: 1,
+            disc_number: 1,
+            year: 1971,
+            id: String::from("xxx"),
+            track_source: TrackSource::Source(spotify::CAPABILITIES),
+            ..test_track(
+                "Black Dog - Remaster",
+                "Led Zeppelin",
+                "Led Zeppelin IV (Remaster)",
+            )
+        };
+
+        // A live recording of the same song from a deluxe edition, listed before the studio version
+        let live = Track {
+            duration: 296,
+            track_number: 1,
+            disc_number: 2,
+            year: 1971,
+            id: String::from("live"),
+            track_source: TrackSource::Library,
+            ..test_track(
+                "Black Dog (Live)",
+                "Led Zeppelin",
+                "Led Zeppelin IV (Deluxe Edition)",
+            )
+        };
+        let studio = Track {
+            title: String::from("Black Dog"),
+            album: String::from("Led Zeppelin IV"),
+            duration: 293,
+            disc_number: 1,
+            id: String::from("studio"),
+            ..live.clone()
+        };
+
+        let library = Library::new(vec![live, studio]);
+        assert_eq!(
+            search(
+                spotify_song,
+                &library,
+                &MatchConfig::default(),
+                &MappingStore::default()
+            )
+            .track
+            .id,
+            "studio"
+        );
+    }
+
+    #[test]
+    fn test_string_comparisons() {
+        // Both strings are normalized before they're compared
+        assert_eq!(Track::string_comparisons("The Bends", "the bends", 20), 20);
+        assert_eq!(Track::string_comparisons("Motörhead", "Motorhead", 20), 20);
+
+        // Anything short of an exact match scores less than the full weight
+        let typo = Track::string_comparisons("Led Zeppelin", "Led Zepplin", 20);
+        assert!((15..20).contains(&typo), "{typo}");
+        assert_eq!(
+            Track::string_comparisons("King Crimson", "Led Zeppelin", 20),
+            0
+        );
+    }
 }
@@ -0,0 +1,108 @@
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+/// Normalize a string so that cosmetic differences between platforms don't affect matching.
+///
+/// - Compatibility characters are folded (NFKC), so full-width letters and ligatures compare equal
+/// - Diacritics on latin letters are stripped ("Motörhead" becomes "motorhead"), marks on other
+///   scripts are kept since they change the letter (eg. the dakuten of "バ")
+/// - Apostrophes of any kind are removed ("St. Tristan’s Sword" and "St. Tristan's Sword" are equal)
+/// - "&" is spelled out as "and"
+/// - Quotes, dashes and all other punctuation are treated as whitespace
+/// - A leading "The" is dropped
+/// - Whitespace is collapsed and the result is lowercased
+///
+/// Strings made entirely of punctuation (eg. the band "!!!") would normalize to nothing,
+/// so they fall back to being compared in lowercase instead.
+pub fn normalize(input: &str) -> String {
+    // Apostrophes are removed both before and after folding. Some lookalikes only become an
+    // apostrophe once folded (eg. the full-width "＇"), others fold into a space and an accent ("´")
+    let mut latin = false;
+    let folded: String = input
+        .chars()
+        .filter(|c| !is_apostrophe(*c))
+        .nfkc()
+        .filter(|c| !is_apostrophe(*c))
+        .nfd()
+        .filter(|c| match is_combining_mark(*c) {
+            true => !latin,
+            false => {
+                latin = is_latin(*c);
+                true
+            }
+        })
+        .nfc()
+        .flat_map(char::to_lowercase)
+        .collect();
+
+    let mut spaced = String::with_capacity(folded.len());
+    for c in folded.chars() {
+        match c {
+            '&' => spaced.push_str(" and "),
+            c if c.is_alphanumeric() || is_combining_mark(c) => spaced.push(c),
+            _ => spaced.push(' '),
+        }
+    }
+
+    let mut words: Vec<&str> = spaced.split_whitespace().collect();
+    if words.len() > 1 && words[0] == "the" {
+        words.remove(0);
+    }
+
+    match words.is_empty() {
+        true => input.to_lowercase(),
+        false => words.join(" "),
+    }
+}
+
+// Letters of the latin alphabet, with or without diacritics
+fn is_latin(c: char) -> bool {
+    c.is_ascii_alphabetic() || matches!(c, '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}')
+}
+
+// Straight, curly and other lookalike apostrophes
+fn is_apostrophe(c: char) -> bool {
+    matches!(
+        c,
+        '\'' | '\u{2018}'
+            | '\u{2019}'
+            | '\u{201B}'
+            | '\u{02BC}'
+            | '\u{00B4}'
+            | '\u{0060}'
+            | '\u{2032}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(
+            normalize("St. Tristan's Sword"),
+            normalize("St. Tristan’s Sword")
+        );
+        assert_eq!(normalize("Motörhead"), "motorhead");
+        assert_eq!(normalize("Simon & Garfunkel"), "simon and garfunkel");
+        assert_eq!(normalize("The Bends"), "bends");
+        assert_eq!(
+            normalize("Street Spirit – Fade Out"),
+            "street spirit fade out"
+        );
+        assert_eq!(normalize("  “Heroes”   "), "heroes");
+        assert_eq!(normalize("ＡＢＣ"), "abc");
+        assert_eq!(normalize("Tristan＇s"), "tristans");
+        assert_eq!(normalize("Tristan´s"), "tristans");
+        assert_eq!(normalize("Sigur Rós"), "sigur ros");
+        assert_eq!(normalize("Sơn Tùng"), "son tung");
+        assert_eq!(normalize("!!!"), "!!!");
+        assert_eq!(normalize("The"), "the");
+
+        // Marks that make another letter in other scripts are kept
+        assert_ne!(normalize("ハ"), normalize("バ"));
+        assert_eq!(normalize("バ"), "バ");
+        assert_ne!(normalize("कि"), normalize("क"));
+        assert_eq!(normalize("नमस्ते"), "नमस्ते");
+    }
+}
@@ -0,0 +1,464 @@
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+
+use crate::services::{
+    Track,
+    destination::{Destination, PlaylistSummary},
+};
+
+/// The kind of file playlists are written as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PlaylistFormat {
+    /// Extended M3U with the artist, title and duration of every song
+    #[default]
+    M3u8,
+    /// XML Shareable Playlist Format
+    Xspf,
+}
+
+impl PlaylistFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "m3u8",
+            PlaylistFormat::Xspf => "xspf",
+        }
+    }
+}
+
+/// Rewrites the paths the server reports into paths a local player can open, for when the
+/// library is mounted somewhere else than on the server (eg. "/music=/mnt/nas/music").
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LibraryRoot {
+    /// Start of the paths on the server, paths not starting with it are left as is. When empty,
+    /// every path is taken as relative to the library and `local` is put in front of it.
+    pub server: String,
+    pub local: String,
+}
+
+impl LibraryRoot {
+    /// Parse a root given as "SERVER=LOCAL", or just "LOCAL" for servers reporting paths
+    /// relative to the library
+    pub fn parse(root: &str) -> Self {
+        match root.split_once('=') {
+            Some((server, local)) => Self {
+                server: server.to_string(),
+                local: local.to_string(),
+            },
+            None => Self {
+                server: String::new(),
+                local: root.to_string(),
+            },
+        }
+    }
+
+    pub fn rewrite(&self, path: &str) -> String {
+        // "/music" is the start of "/music/song.flac", but not of "/musicals/song.flac"
+        let rest = path.strip_prefix(&self.server).filter(|rest| {
+            self.server.is_empty()
+                || self.server.ends_with(['/', '\\'])
+                || rest.is_empty()
+                || rest.starts_with(['/', '\\'])
+        });
+        let Some(rest) = rest else {
+            return path.to_string();
+        };
+
+        let rest = rest.trim_start_matches(['/', '\\']);
+        match self.local.is_empty() {
+            true => rest.to_string(),
+            false => format!("{}/{rest}", self.local.trim_end_matches(['/', '\\'])),
+        }
+    }
+}
+
+/// Writes playlists as files for players that read the same music files directly. Songs are
+/// still looked up in the library of another destination, since the files can only list songs
+/// that exist there.
+pub struct PlaylistFiles<D> {
+    library: D,
+    dir: PathBuf,
+    format: PlaylistFormat,
+    root: Option<LibraryRoot>,
+    /// Whether playlists are also created in the wrapped destination, otherwise only the files
+    /// are written
+    mirror: bool,
+}
+
+impl<D: Destination> PlaylistFiles<D> {
+    pub fn new(
+        library: D,
+        dir: PathBuf,
+        format: PlaylistFormat,
+        root: Option<LibraryRoot>,
+        mirror: bool,
+    ) -> Self {
+        Self {
+            library,
+            dir,
+            format,
+            root,
+            mirror,
+        }
+    }
+
+    // Files are named after their playlist, so updating a playlist overwrites its file
+    fn write(&self, name: &str, comment: &str, tracks: &[Track]) -> Result<String, String> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create {}: {e}", self.dir.display()))?;
+
+        let tracks: Vec<Track> = tracks
+            .iter()
+            .map(|track| Track {
+                path: track.path.as_ref().map(|path| match &self.root {
+                    Some(root) => root.rewrite(path),
+                    None => path.clone(),
+                }),
+                ..track.clone()
+            })
+            .collect();
+
+        // Relative paths are looked up next to the playlist file by most players
+        if self.root.is_none()
+            && tracks
+                .iter()
+                .filter_map(|track| track.path.as_deref())
+                .any(|path| !is_absolute(path))
+        {
+            println!(
+                "The server reports song paths relative to its music folder, players will look for them in {}. Use --library-root to point them at your music folder",
+                self.dir.display()
+            );
+        }
+
+        let contents = match self.format {
+            PlaylistFormat::M3u8 => m3u8(name, &tracks),
+            PlaylistFormat::Xspf => xspf(name, comment, &tracks),
+        };
+
+        let path = self
+            .dir
+            .join(format!("{}.{}", file_name(name), self.format.extension()));
+        let path = path.to_string_lossy().to_string();
+
+        std::fs::write(&path, contents).map_err(|e| format!("Failed to write {path}: {e}"))?;
+        println!("Wrote {path}");
+
+        Ok(path)
+    }
+}
+
+impl<D: Destination> Destination for PlaylistFiles<D> {
+    fn id(&self) -> String {
+        match self.mirror {
+            true => self.library.id(),
+            false => format!("files:{}", self.dir.display()),
+        }
+    }
+
+    async fn list_library(&self) -> Result<Vec<Track>, String> {
+        self.library.list_library().await
+    }
+
+    async fn get_track(&self, id: &str) -> Option<Track> {
+        self.library.get_track(id).await
+    }
+
+    async fn search(&self, query: &str) -> Vec<Track> {
+        self.library.search(query).await
+    }
+
+    /// Files are always written in full, so only mirrored playlists are listed to be updated
+    async fn list_playlists(&self) -> Result<Vec<PlaylistSummary>, String> {
+        match self.mirror {
+            true => self.library.list_playlists().await,
+            false => Ok(Vec::new()),
+        }
+    }
+
+    async fn playlist_tracks(&self, id: &str) -> Result<Vec<Track>, String> {
+        match self.mirror {
+            true => self.library.playlist_tracks(id).await,
+            false => Err(String::from("Playlist files can't be read back")),
+        }
+    }
+
+    /// Playlists are identified by their ID in the wrapped destination when they're mirrored,
+    /// and by the path of their file otherwise
+    async fn create_playlist(
+        &self,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<String, String> {
+        let path = self.write(name, comment, tracks)?;
+
+        match self.mirror {
+            true => self.library.create_playlist(name, comment, tracks).await,
+            false => Ok(path),
+        }
+    }
+
+    async fn update_playlist(
+        &self,
+        id: &str,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<(), String> {
+        self.write(name, comment, tracks)?;
+
+        match self.mirror {
+            true => {
+                self.library
+                    .update_playlist(id, name, comment, tracks)
+                    .await
+            }
+            false => Ok(()),
+        }
+    }
+
+    async fn delete_playlist(&self, id: &str) -> Result<(), String> {
+        match self.mirror {
+            true => self.library.delete_playlist(id).await,
+            false => std::fs::remove_file(id).map_err(|e| format!("Failed to delete {id}: {e}")),
+        }
+    }
+
+    async fn list_favorites(&self) -> Result<Vec<Track>, String> {
+        match self.mirror {
+            true => self.library.list_favorites().await,
+            false => Err(String::from(
+                "Favorites can't be written to a playlist file",
+            )),
+        }
+    }
+
+    async fn add_favorites(&self, tracks: &[Track]) -> Result<(), String> {
+        match self.mirror {
+            true => self.library.add_favorites(tracks).await,
+            false => Err(String::from(
+                "Favorites can't be written to a playlist file",
+            )),
+        }
+    }
+
+    async fn remove_favorites(&self, tracks: &[Track]) -> Result<(), String> {
+        match self.mirror {
+            true => self.library.remove_favorites(tracks).await,
+            false => Err(String::from(
+                "Favorites can't be written to a playlist file",
+            )),
+        }
+    }
+}
+
+/// An extended M3U playlist, tracks without a known path are left out
+pub fn m3u8(name: &str, tracks: &[Track]) -> String {
+    let mut playlist = format!("#EXTM3U\n#PLAYLIST:{name}\n");
+
+    for track in tracks {
+        if let Some(path) = &track.path {
+            playlist.push_str(&format!(
+                "#EXTINF:{},{} - {}\n{path}\n",
+                track.duration, track.artist, track.title
+            ));
+        }
+    }
+
+    playlist
+}
+
+/// An XSPF playlist, tracks without a known path are left out
+pub fn xspf(name: &str, comment: &str, tracks: &[Track]) -> String {
+    let mut playlist = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    playlist.push_str(&format!("  <title>{}</title>\n", escape_xml(name)));
+    if !comment.is_empty() {
+        playlist.push_str(&format!(
+            "  <annotation>{}</annotation>\n",
+            escape_xml(comment)
+        ));
+    }
+
+    playlist.push_str("  <trackList>\n");
+    for track in tracks {
+        let Some(path) = &track.path else {
+            continue;
+        };
+
+        playlist.push_str("    <track>\n");
+        playlist.push_str(&format!(
+            "      <location>{}</location>\n",
+            escape_xml(&location(path))
+        ));
+        playlist.push_str(&format!(
+            "      <title>{}</title>\n",
+            escape_xml(&track.title)
+        ));
+        playlist.push_str(&format!(
+            "      <creator>{}</creator>\n",
+            escape_xml(&track.artist)
+        ));
+        playlist.push_str(&format!(
+            "      <album>{}</album>\n",
+            escape_xml(&track.album)
+        ));
+        if track.duration > 0 {
+            playlist.push_str(&format!(
+                "      <duration>{}</duration>\n",
+                track.duration * 1000
+            ));
+        }
+        playlist.push_str("    </track>\n");
+    }
+    playlist.push_str("  </trackList>\n</playlist>\n");
+
+    playlist
+}
+
+// XSPF locations are URIs, absolute paths become file URIs and relative paths stay relative.
+// Windows paths keep their drive letter ("C:\Music" is "file:///C:/Music") and network paths
+// their server ("\\nas\music" is "file://nas/music").
+fn location(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let (drive, path) = match drive_letter(&path) {
+        true => path.split_at(2),
+        false => ("", path.as_str()),
+    };
+
+    let encoded: String = path
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect();
+
+    match (drive.is_empty(), encoded.starts_with('/')) {
+        (false, _) => format!("file:///{drive}{encoded}"),
+        (true, true) => format!("file://{}", encoded.strip_prefix("//").unwrap_or(&encoded)),
+        (true, false) => encoded,
+    }
+}
+
+fn is_absolute(path: &str) -> bool {
+    path.starts_with(['/', '\\']) || drive_letter(path)
+}
+
+// Whether a path starts with a Windows drive letter, like "C:\" or "C:/"
+fn drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'/' | b'\\')
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Playlist names can contain characters that aren't allowed in file names
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{TrackSource, test_track};
+
+    #[test]
+    fn test_playlist_file() {
+        let song = |title: &str, path: Option<&str>| Track {
+            duration: 296,
+            track_number: 1,
+            disc_number: 1,
+            year: 1971,
+            id: String::from(title),
+            path: path.map(String::from),
+            track_source: TrackSource::Library,
+            ..test_track(title, "Led Zeppelin", "Led Zeppelin IV")
+        };
+
+        // Songs without a path can't be listed
+        let tracks = [
+            song(
+                "Black Dog",
+                Some("/music/Led Zeppelin/IV/01 Black Dog.flac"),
+            ),
+            song("Rock and Roll", None),
+            song(
+                "Going to California",
+                Some("/music/Led Zeppelin/IV/07 Going to California.flac"),
+            ),
+        ];
+        assert_eq!(
+            m3u8("IV", &tracks),
+            "#EXTM3U\n#PLAYLIST:IV\n\
+            #EXTINF:296,Led Zeppelin - Black Dog\n/music/Led Zeppelin/IV/01 Black Dog.flac\n\
+            #EXTINF:296,Led Zeppelin - Going to California\n/music/Led Zeppelin/IV/07 Going to California.flac\n"
+        );
+
+        let playlist = xspf("IV", "Zoso & friends", &tracks);
+        assert!(playlist.contains("<annotation>Zoso &amp; friends</annotation>"));
+        assert!(playlist.contains(
+            "<location>file:///music/Led%20Zeppelin/IV/01%20Black%20Dog.flac</location>"
+        ));
+        assert!(playlist.contains("<duration>296000</duration>"));
+        assert!(!playlist.contains("Rock and Roll"));
+
+        // Windows and network paths are valid file URIs too, relative paths stay relative
+        let tracks = [
+            song(
+                "Black Dog",
+                Some(r"C:\Music\Led Zeppelin\01 Black Dog.flac"),
+            ),
+            song(
+                "Misty Mountain Hop",
+                Some(r"\\nas\music\04 Misty Mountain Hop.flac"),
+            ),
+            song("Four Sticks", Some("Led Zeppelin/IV/05 Four Sticks.flac")),
+        ];
+        let playlist = xspf("IV", "", &tracks);
+        assert!(playlist.contains(
+            "<location>file:///C:/Music/Led%20Zeppelin/01%20Black%20Dog.flac</location>"
+        ));
+        assert!(
+            playlist
+                .contains("<location>file://nas/music/04%20Misty%20Mountain%20Hop.flac</location>")
+        );
+        assert!(
+            playlist.contains("<location>Led%20Zeppelin/IV/05%20Four%20Sticks.flac</location>")
+        );
+
+        // Paths are moved to where the library is mounted locally
+        let root = LibraryRoot::parse("/music=/mnt/nas/music/");
+        assert_eq!(
+            root.rewrite("/music/Led Zeppelin/IV/01 Black Dog.flac"),
+            "/mnt/nas/music/Led Zeppelin/IV/01 Black Dog.flac"
+        );
+        assert_eq!(root.rewrite("/other/song.flac"), "/other/song.flac");
+        assert_eq!(root.rewrite("/musicals/song.flac"), "/musicals/song.flac");
+
+        let root = LibraryRoot::parse("/mnt/nas/music");
+        assert_eq!(
+            root.rewrite("Led Zeppelin/IV/01 Black Dog.flac"),
+            "/mnt/nas/music/Led Zeppelin/IV/01 Black Dog.flac"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeSet;
+
+/// Similarities below this are almost always two unrelated strings that happen to share a few letters
+const SIMILARITY_FLOOR: f64 = 0.8;
+
+// A string that only contains the other (eg. "Street Spirit" and "Street Spirit (Fade Out)")
+// is very likely the same thing, but shouldn't be rated as highly as an exact match.
+const SUBSET_DISCOUNT: f64 = 0.9;
+
+/// Rate how similar two normalized strings are, from 0.0 (nothing in common) to 1.0 (equal).
+///
+/// Takes the best of two measures:
+/// - Jaro-Winkler, which is forgiving of typos and small spelling differences ("Led Zepplin")
+/// - Token set ratio, which ignores word order and extra words on either side
+///   ("Pictures of a City (including 42nd at Treadmill)")
+///
+/// Only equal strings are rated 1.0 and anything below SIMILARITY_FLOOR is rated 0.0.
+pub fn similarity(source: &str, target: &str) -> f64 {
+    if source == target {
+        return 1.0;
+    }
+
+    // Jaro-Winkler can round up to 1.0 for long strings that differ by a single character
+    let rating = strsim::jaro_winkler(source, target)
+        .max(token_set_ratio(source, target) * SUBSET_DISCOUNT)
+        .min(0.99);
+
+    match rating >= SIMILARITY_FLOOR {
+        true => rating,
+        false => 0.0,
+    }
+}
+
+// Compares the words both strings have in common against each string as a whole, with the words
+// sorted so that order doesn't matter. If every word of one string appears in the other, the
+// ratio is 1.0.
+fn token_set_ratio(source: &str, target: &str) -> f64 {
+    let source_words: BTreeSet<&str> = source.split_whitespace().collect();
+    let target_words: BTreeSet<&str> = target.split_whitespace().collect();
+
+    let common = join(source_words.intersection(&target_words));
+    let source_only = join(source_words.difference(&target_words));
+    let target_only = join(target_words.difference(&source_words));
+
+    let source_sorted = format!("{common} {source_only}").trim().to_string();
+    let target_sorted = format!("{common} {target_only}").trim().to_string();
+
+    strsim::normalized_levenshtein(&common, &source_sorted)
+        .max(strsim::normalized_levenshtein(&common, &target_sorted))
+        .max(strsim::normalized_levenshtein(
+            &source_sorted,
+            &target_sorted,
+        ))
+}
+
+fn join<'a>(words: impl Iterator<Item = &'a &'a str>) -> String {
+    words.copied().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity() {
+        // Only equal strings are rated 1.0
+        assert_eq!(similarity("bends", "bends"), 1.0);
+
+        // Typos, extra words and reordered words are rated slightly lower
+        let typo = similarity("led zeppelin", "led zepplin");
+        let extra = similarity(
+            "pictures of a city",
+            "pictures of a city including 42nd at treadmill",
+        );
+        let reordered = similarity("city of pictures", "pictures of a city");
+        for rating in [typo, extra, reordered] {
+            assert!((0.75..1.0).contains(&rating), "{rating}");
+        }
+
+        // Unrelated strings are rated nothing
+        assert_eq!(similarity("king crimson", "led zeppelin"), 0.0);
+        assert_eq!(similarity("yesterday", "yellow submarine"), 0.0);
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::services::{Track, TrackSource};
+
+/// What can be relied on in the tracks of a source, so matching rules are keyed on what a source
+/// provides rather than on which source it is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Track numbers restart on every disc, so they only identify a track on single disc albums
+    pub per_disc_track_numbers: bool,
+}
+
+/// Identifies a collection of tracks within a source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollectionId {
+    /// A playlist, by whatever the source identifies it with (eg. a spotify URI or a name)
+    Playlist(String),
+    /// The songs the user saved, like spotify's Liked Songs
+    SavedTracks,
+}
+
+impl Display for CollectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectionId::Playlist(id) => write!(f, "{id}"),
+            CollectionId::SavedTracks => write!(f, "saved-tracks"),
+        }
+    }
+}
+
+/// Details about a collection, without its tracks
+#[derive(Debug, Clone)]
+pub struct CollectionMetadata {
+    pub id: CollectionId,
+    pub name: String,
+    pub description: String,
+    /// Left empty by sources that don't know who owns a collection
+    pub owner_id: String,
+    pub owner_name: Option<String>,
+    pub collaborative: bool,
+    /// The version of the collection, if the source keeps track of it (eg. spotify's snapshot ID)
+    pub snapshot: Option<String>,
+    /// How many items the source lists, including ones that can't be imported (eg. episodes)
+    pub total: u32,
+}
+
+/// A collection together with its tracks, in order
+#[derive(Debug, Clone)]
+pub struct Collection {
+    pub metadata: CollectionMetadata,
+    pub tracks: Vec<Track>,
+}
+
+/// A service or file that playlists can be imported from. New sources only need to implement
+/// this trait to go through the same matching and import as every other source.
+pub trait SourceProvider {
+    /// Identifies the kind of source, so collections imported from it can be found again
+    fn name(&self) -> &'static str;
+
+    /// What can be relied on in the tracks of this source
+    fn capabilities(&self) -> Capabilities;
+
+    /// Every playlist the source offers. Saved tracks aren't listed, they're always available
+    /// as `CollectionId::SavedTracks`
+    async fn list_collections(&self) -> Result<Vec<CollectionMetadata>, String>;
+
+    /// Details about a single collection, fails if the source doesn't have it
+    async fn collection_metadata(&self, id: &CollectionId) -> Result<CollectionMetadata, String>;
+
+    /// Every track of a collection that can be imported, in order
+    async fn fetch_tracks(&self, id: &CollectionId) -> Result<Vec<Track>, String>;
+
+    /// Fetch the tracks of a collection, marked with the capabilities of this source
+    async fn fetch_collection(&self, metadata: CollectionMetadata) -> Result<Collection, String> {
+        let mut tracks = self.fetch_tracks(&metadata.id).await?;
+
+        let capabilities = self.capabilities();
+        for track in &mut tracks {
+            track.track_source = TrackSource::Source(capabilities);
+        }
+
+        Ok(Collection { metadata, tracks })
+    }
+}
+
+/// Whether collaborative playlists are imported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Collaborative {
+    /// Import collaborative playlists along with the rest
+    #[default]
+    Include,
+    /// Leave collaborative playlists out
+    Exclude,
+    /// Only import collaborative playlists
+    Only,
+}
+
+/// Decides which of the user's playlists are imported. A playlist is imported when it matches
+/// the include filters (if any) and none of the exclude filters.
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistFilter {
+    pub include_name: Option<Regex>,
+    pub exclude_name: Option<Regex>,
+    /// Owner IDs or display names, compared case insensitively
+    pub include_owners: Vec<String>,
+    pub exclude_owners: Vec<String>,
+    pub collaborative: Collaborative,
+}
+
+impl PlaylistFilter {
+    pub fn matches(&self, playlist: &CollectionMetadata) -> bool {
+        let is_owner = |owner: &String| {
+            owner.eq_ignore_ascii_case(&playlist.owner_id)
+                || playlist
+                    .owner_name
+                    .as_ref()
+                    .is_some_and(|name| owner.eq_ignore_ascii_case(name))
+        };
+
+        let collaborative = match self.collaborative {
+            Collaborative::Include => true,
+            Collaborative::Exclude => !playlist.collaborative,
+            Collaborative::Only => playlist.collaborative,
+        };
+
+        collaborative
+            && self
+                .include_name
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(&playlist.name))
+            && !self
+                .exclude_name
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&playlist.name))
+            && (self.include_owners.is_empty() || self.include_owners.iter().any(is_owner))
+            && !self.exclude_owners.iter().any(is_owner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playlist_filter() {
+        let mixtape = CollectionMetadata {
+            id: CollectionId::Playlist(String::from("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M")),
+            name: String::from("Road Trip Mix"),
+            description: String::new(),
+            owner_id: String::from("ledzeppelinlover67"),
+            owner_name: Some(String::from("Jimmy")),
+            collaborative: false,
+            snapshot: None,
+            total: 42,
+        };
+        let shared = CollectionMetadata {
+            name: String::from("Party Mix"),
+            owner_id: String::from("spotify"),
+            owner_name: Some(String::from("Spotify")),
+            collaborative: true,
+            ..mixtape.clone()
+        };
+
+        assert!(PlaylistFilter::default().matches(&mixtape));
+        assert!(PlaylistFilter::default().matches(&shared));
+
+        let filter = PlaylistFilter {
+            include_name: Some(Regex::new("(?i)mix$").unwrap()),
+            exclude_name: Some(Regex::new("Party").unwrap()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&mixtape));
+        assert!(!filter.matches(&shared));
+
+        // Owners are found by their ID or display name
+        let filter = PlaylistFilter {
+            include_owners: vec![String::from("jimmy")],
+            ..Default::default()
+        };
+        assert!(filter.matches(&mixtape));
+        assert!(!filter.matches(&shared));
+
+        let filter = PlaylistFilter {
+            exclude_owners: vec![String::from("Spotify")],
+            ..Default::default()
+        };
+        assert!(filter.matches(&mixtape));
+        assert!(!filter.matches(&shared));
+
+        let filter = PlaylistFilter {
+            collaborative: Collaborative::Only,
+            ..Default::default()
+        };
+        assert!(!filter.matches(&mixtape));
+        assert!(filter.matches(&shared));
+
+        let filter = PlaylistFilter {
+            collaborative: Collaborative::Exclude,
+            ..Default::default()
+        };
+        assert!(filter.matches(&mixtape));
+        assert!(!filter.matches(&shared));
+    }
+}
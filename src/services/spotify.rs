@@ -1,5 +1,16 @@
 use futures::TryStreamExt;
 use rspotify::{AuthCodeSpotify, Config, Credentials, OAuth, prelude::*, scopes};
+use rspotify_model::{PlayableItem, PlaylistId, TrackId};
+
+use crate::services::{
+    Track,
+    source::{Capabilities, CollectionId, CollectionMetadata, SourceProvider},
+};
+
+/// Spotify numbers tracks per disc
+pub const CAPABILITIES: Capabilities = Capabilities {
+    per_disc_track_numbers: true,
+};
 
 /// Login to spotify and return the instance
 pub async fn login_spotify(id: String, secret: String) -> AuthCodeSpotify {
@@ -9,7 +20,8 @@ pub async fn login_spotify(id: String, secret: String) -> AuthCodeSpotify {
         redirect_uri: "http://127.0.0.1:8888/callback".to_string(),
         scopes: scopes!(
             "playlist-read-private",
-            "playlist-read-collaborative" // "user-library-read"
+            "playlist-read-collaborative",
+            "user-library-read"
         ),
         ..Default::default()
     };
@@ -27,11 +39,179 @@ pub async fn login_spotify(id: String, secret: String) -> AuthCodeSpotify {
     spotify
 }
 
-#[allow(unused)]
-pub async fn list_playlists(client: &AuthCodeSpotify) {
-    let mut stream = client.current_user_playlists();
+/// Imports the playlists and Liked Songs of a spotify account
+pub struct SpotifySource {
+    client: AuthCodeSpotify,
+}
+
+impl SpotifySource {
+    pub fn new(client: AuthCodeSpotify) -> Self {
+        Self { client }
+    }
+}
+
+/// Check whether a playlist ID or URI is valid without fetching it
+pub fn check_playlist_id(id: &str) -> Result<(), String> {
+    PlaylistId::from_id_or_uri(id)
+        .map(|_| ())
+        .map_err(|e| format!("Error converting playlist to ID {e}"))
+}
+
+/// The URI of a track given by its ID or URI, the way tracks from spotify are identified
+pub fn track_uri(id: &str) -> Result<String, String> {
+    TrackId::from_id_or_uri(id)
+        .map(|id| id.to_string())
+        .map_err(|e| format!("Invalid spotify track ID {id}! {e}"))
+}
+
+/// Whether an ID looks like a spotify track ID or URI rather than the ID of a track from another
+/// source (eg. "csv:Artist - Title")
+pub fn is_track_id(id: &str) -> bool {
+    id.starts_with("spotify:") || (id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+impl SourceProvider for SpotifySource {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        CAPABILITIES
+    }
+
+    /// Every playlist in the user's library, owned or followed
+    async fn list_collections(&self) -> Result<Vec<CollectionMetadata>, String> {
+        let mut stream = self.client.current_user_playlists();
+        let mut playlists = Vec::new();
+
+        while let Some(playlist) = stream
+            .try_next()
+            .await
+            .map_err(|e| format!("Failed to get playlists from spotify! {e}"))?
+        {
+            playlists.push(CollectionMetadata {
+                id: CollectionId::Playlist(playlist.id.to_string()),
+                name: playlist.name,
+                // Only full playlists have a description
+                description: String::new(),
+                owner_id: playlist.owner.id.id().to_string(),
+                owner_name: playlist.owner.display_name,
+                collaborative: playlist.collaborative,
+                snapshot: Some(playlist.snapshot_id),
+                total: playlist.tracks.total,
+            });
+        }
+
+        Ok(playlists)
+    }
+
+    async fn collection_metadata(&self, id: &CollectionId) -> Result<CollectionMetadata, String> {
+        let playlist_id = match id {
+            CollectionId::Playlist(id) => {
+                PlaylistId::from_id_or_uri(id).map_err(|e| e.to_string())?
+            }
+            CollectionId::SavedTracks => {
+                let page = self
+                    .client
+                    .current_user_saved_tracks_manual(None, Some(1), None)
+                    .await
+                    .map_err(|e| format!("Failed to get saved tracks from spotify! {e}"))?;
+
+                return Ok(CollectionMetadata {
+                    id: CollectionId::SavedTracks,
+                    name: String::from("Liked Songs"),
+                    description: String::new(),
+                    owner_id: String::new(),
+                    owner_name: None,
+                    collaborative: false,
+                    snapshot: None,
+                    total: page.total,
+                });
+            }
+        };
+
+        let playlist = self
+            .client
+            .playlist(playlist_id, None, None)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(CollectionMetadata {
+            // Playlists given by their ID are identified by their URI like listed playlists
+            id: CollectionId::Playlist(playlist.id.to_string()),
+            name: playlist.name,
+            description: playlist.description.unwrap_or_default(),
+            owner_id: playlist.owner.id.id().to_string(),
+            owner_name: playlist.owner.display_name,
+            collaborative: playlist.collaborative,
+            snapshot: Some(playlist.snapshot_id),
+            total: playlist.tracks.total,
+        })
+    }
+
+    async fn fetch_tracks(&self, id: &CollectionId) -> Result<Vec<Track>, String> {
+        let mut tracks = Vec::new();
+
+        let playlist_id = match id {
+            CollectionId::Playlist(id) => {
+                PlaylistId::from_id_or_uri(id).map_err(|e| e.to_string())?
+            }
+            CollectionId::SavedTracks => {
+                let mut stream = self.client.current_user_saved_tracks(None);
+                while let Some(saved) = stream
+                    .try_next()
+                    .await
+                    .map_err(|e| format!("Failed to get saved tracks from spotify! {e}"))?
+                {
+                    if let Ok(track) = saved.track.try_into() {
+                        tracks.push(track);
+                    }
+                }
+
+                return Ok(tracks);
+            }
+        };
+
+        let mut offset = 0;
+
+        // Turn all spotify tracks into a Track type and add them to the collection
+        loop {
+            let page = self
+                .client
+                .playlist_items_manual(playlist_id.clone(), None, None, Some(50), Some(offset))
+                .await
+                .map_err(|e| format!("Failed to get page of tracks from spotify! {e}"))?;
+
+            for item in page.items {
+                if let Some(PlayableItem::Track(track)) = item.track {
+                    // Turn source track into a Track
+                    if let Ok(track) = track.try_into() {
+                        tracks.push(track);
+                    }
+                }
+            }
+
+            if page.next.is_none() {
+                break;
+            }
+
+            offset += 50
+        }
+
+        Ok(tracks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    while let Ok(Some(playlist)) = stream.try_next().await {
-        println!("{} {}", playlist.name, playlist.id);
+    #[test]
+    fn test_track_ids() {
+        assert!(is_track_id("4iV5W9uYEdYUVa79Axb7Rh"));
+        assert!(is_track_id("spotify:track:4iV5W9uYEdYUVa79Axb7Rh"));
+        // Tracks from files without URIs are remembered by their artist and title
+        assert!(!is_track_id("csv:Led Zeppelin - Ramble On"));
+        assert!(!is_track_id("Ramble On"));
     }
 }
@@ -1,5 +1,36 @@
-use crate::services::Track;
-use submarine::{Client, SubsonicError, auth::AuthBuilder, data::Info};
+use std::collections::{HashMap, HashSet};
+
+use crate::services::{
+    Track, TrackSource,
+    cache::{AlbumSummary, CacheMode, CachedAlbum, LibraryCache},
+    destination::{Destination, PlaylistSummary},
+    split_artists,
+    sync::PlaylistEdit,
+};
+use clap::ValueEnum;
+use futures::StreamExt;
+use submarine::{
+    AlbumListType, Client,
+    auth::AuthBuilder,
+    data::{AlbumId3, Child},
+};
+
+/// How many albums or artists are fetched at once when listing the library by album
+const CONCURRENT_REQUESTS: usize = 8;
+
+/// How the songs of a library are listed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LibraryScan {
+    /// Use the quickest method the server supports
+    #[default]
+    Auto,
+    /// Search with an empty query, only some servers (eg. Navidrome) return every song for it
+    Search,
+    /// List every album with getAlbumList2, then fetch the songs of each
+    Albums,
+    /// List every artist with getArtists, then their albums and the songs of each album
+    Artists,
+}
 
 /// Login to subsonic server
 pub fn login_subsonic(url: String, user: String, pass: String) -> Client {
@@ -10,79 +41,659 @@ pub fn login_subsonic(url: String, user: String, pass: String) -> Client {
     Client::new(&url, auth)
 }
 
-/// Fetch all songs from subsonic
-pub async fn fetch_subsonic_songs(client: &Client) -> Vec<Track> {
-    let mut all_songs: Vec<Track> = Vec::new();
+/// Fetch all songs from subsonic album by album, listing them the way the server supports.
+/// Fails when no songs were found even though the server lists albums, rather than importing
+/// into what looks like an empty library.
+pub async fn fetch_subsonic_songs(
+    client: &Client,
+    scan: LibraryScan,
+) -> Result<Vec<CachedAlbum>, String> {
+    let scan = match scan {
+        LibraryScan::Auto => detect_scan(client).await,
+        scan => scan,
+    };
+
+    let albums = match scan {
+        LibraryScan::Auto | LibraryScan::Search => search_all_songs(client).await?,
+        LibraryScan::Albums => {
+            let albums = list_albums(client).await?;
+            fetch_album_songs(client, albums.into_iter().map(|album| album.id).collect()).await
+        }
+        LibraryScan::Artists => {
+            let albums = list_artist_albums(client).await?;
+            fetch_album_songs(client, albums).await
+        }
+    };
+
+    if albums.iter().all(|album| album.tracks.is_empty()) && lists_albums(client).await {
+        let scan = scan
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        return Err(format!(
+            "The server has albums, but listing the library by {scan} found no songs! Try another --library-scan"
+        ));
+    }
+
+    Ok(albums)
+}
+
+/// Picks how to list the library, see `choose_scan`
+async fn detect_scan(client: &Client) -> LibraryScan {
+    let search = client
+        .search3("", Some(0), None, Some(0), None, Some(1), None, Some(""))
+        .await;
+    let search_finds_songs = search.is_ok_and(|results| !results.song.is_empty());
+
+    // Albums only need to be listed when searching doesn't work
+    let scan = choose_scan(
+        search_finds_songs,
+        search_finds_songs || lists_albums(client).await,
+    );
+
+    match scan {
+        LibraryScan::Albums => {
+            println!("Server doesn't return songs for an empty search, listing albums instead")
+        }
+        LibraryScan::Artists => {
+            println!("Server doesn't return songs for an empty search, listing artists instead")
+        }
+        _ => {}
+    }
+    scan
+}
+
+/// How to list the library of a server. Searching is quickest, but only some servers
+/// (eg. Navidrome) return every song for an empty query, others return nothing or refuse it.
+/// Those are listed album by album, or artist by artist when they can't list albums either.
+pub fn choose_scan(search_finds_songs: bool, lists_albums: bool) -> LibraryScan {
+    match (search_finds_songs, lists_albums) {
+        (true, _) => LibraryScan::Search,
+        (false, true) => LibraryScan::Albums,
+        (false, false) => LibraryScan::Artists,
+    }
+}
+
+// Whether the server lists any album with getAlbumList2
+async fn lists_albums(client: &Client) -> bool {
+    client
+        .get_album_list2(
+            AlbumListType::AlphabeticalByName,
+            Some(1),
+            None,
+            None::<String>,
+        )
+        .await
+        .is_ok_and(|albums| !albums.is_empty())
+}
+
+// Providing an empty search string returns all results on servers that support it. Songs are
+// grouped by the album the search lists them on, in the order they were found.
+async fn search_all_songs(client: &Client) -> Result<Vec<CachedAlbum>, String> {
+    let albums = search_all_albums(client).await;
+    let mut all_albums: Vec<CachedAlbum> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
     let mut offset = 0;
 
     loop {
-        // Providing an empty search string returns all results
-        let search_results = match client
+        let search_results = client
             .search3("", None, None, None, None, None, Some(offset), Some(""))
             .await
-        {
-            Ok(r) => r.song,
-            Err(e) => {
-                println!("Error while searching for songs! {}", e);
-                std::process::exit(1)
-            }
-        };
+            .map_err(|e| format!("Error while searching for songs! {e}"))?
+            .song;
 
         if !search_results.is_empty() {
             offset += 20;
 
-            search_results
-                .into_iter()
-                .filter_map(|s| s.try_into().ok())
-                .for_each(|song| all_songs.push(song));
+            for song in search_results {
+                let album_id = song.album_id.clone().unwrap_or_default();
+                let album = albums.get(&album_id);
+                let original_year = album.and_then(earliest_year);
+
+                let Ok(mut track) = Track::try_from(song) else {
+                    continue;
+                };
+                track.original_year = original_year.filter(|year| *year < track.year);
+
+                // Songs of albums the search didn't list are cached with only the album's ID
+                let position = *positions.entry(album_id.clone()).or_insert_with(|| {
+                    all_albums.push(CachedAlbum {
+                        album: album.map(album_summary).unwrap_or(AlbumSummary {
+                            id: album_id,
+                            ..Default::default()
+                        }),
+                        tracks: Vec::new(),
+                    });
+                    all_albums.len() - 1
+                });
+                all_albums[position].tracks.push(track);
+            }
         } else {
             break;
         }
     }
 
-    all_songs
+    Ok(all_albums)
+}
+
+// Every album of the library, a page at a time
+async fn list_albums(client: &Client) -> Result<Vec<AlbumId3>, String> {
+    let mut all_albums = Vec::new();
+
+    loop {
+        let albums = client
+            .get_album_list2(
+                AlbumListType::AlphabeticalByName,
+                Some(500),
+                Some(all_albums.len()),
+                None::<String>,
+            )
+            .await
+            .map_err(|e| format!("Error while listing albums! {e}"))?;
+
+        if albums.is_empty() {
+            break;
+        }
+        all_albums.extend(albums);
+    }
+
+    Ok(all_albums)
+}
+
+// The ID of every album of every artist, for servers that can't list albums by themselves
+async fn list_artist_albums(client: &Client) -> Result<Vec<String>, String> {
+    let indexes = client
+        .get_artists(None::<String>)
+        .await
+        .map_err(|e| format!("Error while listing artists! {e}"))?;
+
+    let artists: Vec<_> = indexes.into_iter().flat_map(|index| index.artist).collect();
+
+    let artists: Vec<_> = futures::stream::iter(artists)
+        .map(|artist| client.get_artist(artist.id))
+        .buffered(CONCURRENT_REQUESTS)
+        .collect()
+        .await;
+
+    let mut albums = Vec::new();
+    for artist in artists {
+        let artist = artist.map_err(|e| format!("Error while fetching an artist! {e}"))?;
+        albums.extend(artist.album.into_iter().map(|album| album.id));
+    }
+
+    // Albums credited to several artists are listed once for each of them
+    Ok(unique_ids(albums))
+}
+
+/// Leaves out every ID that was already listed, keeping the order they were first listed in
+pub fn unique_ids(ids: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    ids.into_iter()
+        .filter(|id| seen.insert(id.clone()))
+        .collect()
+}
+
+// Fetches the songs of every album, their original year is taken from the album. Albums that
+// can't be fetched are skipped, so one broken album doesn't keep the rest of the library out.
+async fn fetch_album_songs(client: &Client, ids: Vec<String>) -> Vec<CachedAlbum> {
+    let albums: Vec<_> = futures::stream::iter(ids)
+        .map(|id| async move { (client.get_album(&id).await, id) })
+        .buffered(CONCURRENT_REQUESTS)
+        .collect()
+        .await;
+
+    let mut all_albums = Vec::new();
+    for (album, id) in albums {
+        let album = match album {
+            Ok(album) => album,
+            Err(e) => {
+                println!("Skipped album {id}, it couldn't be fetched! {e}");
+                continue;
+            }
+        };
+        let original_year = earliest_year(&album.base);
+
+        let tracks = album
+            .song
+            .into_iter()
+            .filter_map(|song| Track::try_from(song).ok())
+            .map(|track| Track {
+                original_year: original_year.filter(|year| *year < track.year),
+                ..track
+            })
+            .collect();
+
+        all_albums.push(CachedAlbum {
+            album: album_summary(&album.base),
+            tracks,
+        });
+    }
+
+    all_albums
+}
+
+fn album_summary(album: &AlbumId3) -> AlbumSummary {
+    AlbumSummary {
+        id: album.id.clone(),
+        name: album.name.clone(),
+        year: album.year,
+        song_count: album.song_count,
+        duration: album.duration,
+    }
+}
+
+/// Fetch every album by its ID, to know the earliest release year of each. OpenSubsonic servers
+/// report the original release date of an album, which is often earlier than the year its songs
+/// are tagged with (eg. the year an album was remastered). Plain subsonic servers only report the
+/// tagged year.
+async fn search_all_albums(client: &Client) -> HashMap<String, AlbumId3> {
+    let mut all_albums = HashMap::new();
+    let mut offset = 0;
+
+    loop {
+        // Providing an empty search string returns all results
+        let albums = match client
+            .search3(
+                "",
+                Some(0),
+                None,
+                Some(500),
+                Some(offset),
+                Some(0),
+                None,
+                Some(""),
+            )
+            .await
+        {
+            Ok(r) => r.album,
+            // Not fatal, matching only uses the tagged year then
+            Err(_) => break,
+        };
+
+        if albums.is_empty() {
+            break;
+        }
+
+        offset += albums.len();
+
+        for album in albums {
+            all_albums.insert(album.id.clone(), album);
+        }
+    }
+
+    all_albums
 }
 
-/// Creates the playlist and adds the song ID's of matched tracks
-pub async fn create_playlist(
+// The earliest of the release dates reported for an album
+fn earliest_year(album: &AlbumId3) -> Option<i32> {
+    [
+        album
+            .original_release_date
+            .as_ref()
+            .and_then(|date| date.year),
+        album.release_date.as_ref().and_then(|date| date.year),
+        album.year,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|year| *year > 0)
+    .min()
+}
+
+/// Fetch the library, from the cache when it can be used. A cached library is brought up to date
+/// when the server reports it changed, by fetching the albums that were added or changed since it
+/// was saved.
+async fn load_library(
     client: &Client,
-    name: String,
-    comment: String,
-    tracks: Vec<Track>,
-) -> Result<Info, SubsonicError> {
-    let empty_vec: Vec<String> = Vec::new();
-    let playlist_id = client.create_playlist(name, empty_vec).await?.base.id;
+    library: &str,
+    mode: CacheMode,
+    scan: LibraryScan,
+) -> Result<Vec<Track>, String> {
+    let cached = match mode {
+        CacheMode::Use => LibraryCache::load(library),
+        CacheMode::Refresh | CacheMode::Off => None,
+    };
 
-    client
-        .update_playlist(
-            playlist_id,
-            Some(""),
-            Some(comment),
-            Some(false),
-            tracks.into_iter().map(|t| t.id).collect(),
-            vec![],
+    // Asked for before fetching anything, so nothing added meanwhile is missed next time
+    let last_modified = client
+        .get_indexes(
+            None::<String>,
+            cached.as_ref().and_then(|c| c.last_modified),
         )
         .await
+        .ok()
+        .map(|indexes| indexes.last_modified);
+
+    let cache = match cached {
+        Some(cache) if last_modified.is_some() && cache.last_modified == last_modified => {
+            println!("Library unchanged since it was cached");
+            return Ok(cache.tracks());
+        }
+        Some(cache) => match refresh_library(client, cache).await {
+            Some(cache) => cache,
+            None => fetch_library(client, library, scan).await?,
+        },
+        None => fetch_library(client, library, scan).await?,
+    };
+
+    let cache = LibraryCache {
+        last_modified,
+        ..cache
+    };
+
+    if mode != CacheMode::Off
+        && let Err(e) = cache.save()
+    {
+        println!("Error saving library cache! {e}");
+    }
+
+    Ok(cache.tracks())
 }
 
-/// Adds a list of songs to the "favorites" list
-pub async fn add_songs_to_favorites(
+async fn fetch_library(
     client: &Client,
-    tracks: Vec<Track>,
-) -> Result<Info, SubsonicError> {
-    let albums: Vec<String> = Vec::new();
-    let artists: Vec<String> = Vec::new();
+    library: &str,
+    scan: LibraryScan,
+) -> Result<LibraryCache, String> {
+    Ok(LibraryCache {
+        library: library.to_string(),
+        last_modified: None,
+        albums: fetch_subsonic_songs(client, scan).await?,
+    })
+}
 
-    client
-        .star(tracks.into_iter().map(|t| t.id).collect(), albums, artists)
+// Drops the songs of albums that were removed or changed since the cache was saved, and fetches
+// the songs of albums that were added or changed. Returns nothing when the albums can't be
+// listed or the change can't be narrowed down to albums, the whole library has to be fetched then.
+async fn refresh_library(client: &Client, mut cache: LibraryCache) -> Option<LibraryCache> {
+    let current: Vec<AlbumSummary> = list_albums(client)
         .await
+        .ok()?
+        .iter()
+        .map(album_summary)
+        .collect();
+
+    let previous = cache.albums.len();
+    let changed = cache.reconcile(&current)?;
+    let removed = previous - cache.albums.len();
+
+    let albums = fetch_album_songs(client, changed).await;
+    println!(
+        "Fetched {} albums that were added or changed since the library was cached, {} were removed or changed",
+        albums.len(),
+        removed
+    );
+    cache.albums.extend(albums);
+
+    Some(cache)
 }
 
-/// Gets a single song from subsonic using an ID
-pub async fn get_song(client: &Client, id: &str) -> Option<Track> {
-    client
-        .get_song(id)
-        .await
-        .ok()
-        .and_then(|song| song.try_into().ok())
+/// Imports into the library and playlists of a subsonic server
+pub struct SubsonicDestination {
+    client: Client,
+    url: String,
+    user: String,
+    cache: CacheMode,
+    scan: LibraryScan,
+}
+
+impl SubsonicDestination {
+    pub fn login(
+        url: String,
+        user: String,
+        pass: String,
+        cache: CacheMode,
+        scan: LibraryScan,
+    ) -> Self {
+        Self {
+            client: login_subsonic(url.clone(), user.clone(), pass),
+            url,
+            user,
+            cache,
+            scan,
+        }
+    }
+}
+
+impl Destination for SubsonicDestination {
+    fn id(&self) -> String {
+        format!("{}@{}", self.user, self.url.trim_end_matches('/'))
+    }
+
+    async fn list_library(&self) -> Result<Vec<Track>, String> {
+        load_library(&self.client, &self.id(), self.cache, self.scan).await
+    }
+
+    async fn get_track(&self, id: &str) -> Option<Track> {
+        self.client
+            .get_song(id)
+            .await
+            .ok()
+            .and_then(|song| song.try_into().ok())
+    }
+
+    /// Returns the first page of results
+    async fn search(&self, query: &str) -> Vec<Track> {
+        match self
+            .client
+            .search3(
+                query,
+                Some(0),
+                None,
+                Some(0),
+                None,
+                Some(20),
+                None,
+                Some(""),
+            )
+            .await
+        {
+            Ok(r) => r
+                .song
+                .into_iter()
+                .filter_map(|s| s.try_into().ok())
+                .collect(),
+            Err(e) => {
+                println!("Error while searching for songs! {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn list_playlists(&self) -> Result<Vec<PlaylistSummary>, String> {
+        let playlists = self
+            .client
+            .get_playlists(None::<String>)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(playlists
+            .into_iter()
+            .map(|playlist| PlaylistSummary {
+                id: playlist.id,
+                name: playlist.name,
+                comment: playlist.comment.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn playlist_tracks(&self, id: &str) -> Result<Vec<Track>, String> {
+        let playlist = self
+            .client
+            .get_playlist(id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(playlist.entry.into_iter().map(playlist_entry).collect())
+    }
+
+    /// Creates the playlist and adds the song ID's of matched tracks
+    async fn create_playlist(
+        &self,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<String, String> {
+        let empty_vec: Vec<String> = Vec::new();
+        let playlist_id = self
+            .client
+            .create_playlist(name, empty_vec)
+            .await
+            .map_err(|e| e.to_string())?
+            .base
+            .id;
+
+        self.client
+            .update_playlist(
+                playlist_id.clone(),
+                Some(""),
+                Some(comment),
+                Some(false),
+                tracks.iter().map(|t| t.id.clone()).collect(),
+                vec![],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(playlist_id)
+    }
+
+    /// Removes the songs that aren't wanted anymore and adds the new ones at the end. When songs
+    /// were reordered or have to be inserted between others, every song of the playlist is removed
+    /// and the tracks are added in their place, which is the only way to reorder a playlist
+    /// through the subsonic API.
+    async fn update_playlist(
+        &self,
+        id: &str,
+        name: &str,
+        comment: &str,
+        tracks: &[Track],
+    ) -> Result<(), String> {
+        let playlist = self
+            .client
+            .get_playlist(id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let current: Vec<Track> = playlist.entry.into_iter().map(playlist_entry).collect();
+        let edit = PlaylistEdit::new(&current, tracks).unwrap_or_else(|| PlaylistEdit {
+            remove: (0..current.len()).collect(),
+            append: tracks.to_vec(),
+        });
+
+        self.client
+            .update_playlist(
+                id,
+                Some(name),
+                Some(comment),
+                None,
+                edit.append.iter().map(|t| t.id.clone()).collect(),
+                edit.remove.iter().map(|&index| index as i64).collect(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    async fn delete_playlist(&self, id: &str) -> Result<(), String> {
+        self.client
+            .delete_playlist(id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// Lists the songs starred by the user
+    async fn list_favorites(&self) -> Result<Vec<Track>, String> {
+        let starred = self
+            .client
+            .get_starred2(None::<String>)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(starred.song.into_iter().map(playlist_entry).collect())
+    }
+
+    /// Adds a list of songs to the "favorites" list
+    async fn add_favorites(&self, tracks: &[Track]) -> Result<(), String> {
+        let albums: Vec<String> = Vec::new();
+        let artists: Vec<String> = Vec::new();
+
+        self.client
+            .star(
+                tracks.iter().map(|t| t.id.clone()).collect(),
+                albums,
+                artists,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// Removes a list of songs from the "favorites" list
+    async fn remove_favorites(&self, tracks: &[Track]) -> Result<(), String> {
+        let albums: Vec<String> = Vec::new();
+        let artists: Vec<String> = Vec::new();
+
+        self.client
+            .unstar(
+                tracks.iter().map(|t| t.id.clone()).collect(),
+                albums,
+                artists,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+// Songs in a playlist or the favorites are kept even when they lack the details needed for
+// matching, so every entry can be compared against the tracks being imported
+fn playlist_entry(song: Child) -> Track {
+    match Track::try_from(song.clone()) {
+        Ok(track) => track,
+        Err(()) => {
+            let artist = song.artist.unwrap_or_default();
+            Track {
+                title: song.title,
+                artists: split_artists(&artist),
+                artist,
+                album: song.album.unwrap_or_default(),
+                duration: song.duration.unwrap_or_default(),
+                track_number: song.track.unwrap_or_default() as u32,
+                disc_number: song.disc_number.unwrap_or_default() as u32,
+                year: song.year.unwrap_or_default(),
+                original_year: None,
+                id: song.id,
+                isrc: None,
+                musicbrainz_id: None,
+                format: song.suffix,
+                path: song.path,
+                track_source: TrackSource::Library,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_scan() {
+        assert_eq!(choose_scan(true, true), LibraryScan::Search);
+        // Gonic and Airsonic-Advanced don't return songs for an empty search
+        assert_eq!(choose_scan(false, true), LibraryScan::Albums);
+        assert_eq!(choose_scan(false, false), LibraryScan::Artists);
+
+        // Albums credited to two artists are listed by both of them, but only fetched once
+        let ids = ["al-1", "al-2", "al-1", "al-3", "al-2"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(unique_ids(ids), ["al-1", "al-2", "al-3"].map(String::from));
+    }
 }
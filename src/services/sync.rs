@@ -0,0 +1,501 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::Track;
+
+/// A playlist created by an earlier import, so a later import can update it instead of creating
+/// a duplicate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportedPlaylist {
+    /// The source provider the playlist was imported from (eg. "spotify")
+    pub provider: String,
+    /// The collection within the source, as given by `CollectionId`'s display
+    pub collection: String,
+    /// The destination the playlist was created in, see `Destination::id`
+    pub destination: String,
+    /// The ID of the created playlist within the destination
+    pub playlist_id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// Songs TuneTracker added to the favorites of a destination when importing a collection. Songs
+/// that were already favorited aren't listed, those were favorited by the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StarredSongs {
+    pub provider: String,
+    pub collection: String,
+    pub destination: String,
+    /// The song added for each track of the collection, by the ID of the track in the source
+    #[serde(default)]
+    pub tracks: BTreeMap<String, String>,
+}
+
+impl StarredSongs {
+    /// Works out how the favorites change when the collection is imported again, and remembers
+    /// the songs added for it. `source` holds the ID of every track the collection has now, and
+    /// `matched` the song each track that matched was matched to (source ID, song ID).
+    ///
+    /// Returns the songs to add to the favorites and, when syncing, the songs to remove. A song is
+    /// only removed once no track of the collection stands for it anymore, because its track was
+    /// removed at the source or now matches another song. Tracks that didn't match this time
+    /// (eg. they were skipped) keep their song.
+    pub fn update(
+        &mut self,
+        source: &HashSet<&str>,
+        matched: &[(&str, &str)],
+        favorites: &HashSet<&str>,
+        sync: bool,
+    ) -> (Vec<String>, Vec<String>) {
+        let previous = std::mem::take(&mut self.tracks);
+        let matched_songs: HashMap<&str, &str> = matched.iter().copied().collect();
+
+        // Songs the user removed from the favorites since are forgotten
+        for (track, song) in &previous {
+            let rematched = matched_songs
+                .get(track.as_str())
+                .is_some_and(|matched| matched != song);
+            if favorites.contains(song.as_str()) && source.contains(track.as_str()) && !rematched {
+                self.tracks.insert(track.clone(), song.clone());
+            }
+        }
+
+        let ours: HashSet<&str> = previous.values().map(String::as_str).collect();
+        let mut added: Vec<String> = Vec::new();
+        for (track, song) in matched {
+            if self.tracks.contains_key(*track) {
+                continue;
+            }
+
+            let new = !favorites.contains(song);
+            if new && !added.iter().any(|added| added == song) {
+                added.push(song.to_string());
+            }
+            if new || ours.contains(song) {
+                self.tracks.insert(track.to_string(), song.to_string());
+            }
+        }
+
+        let kept: HashSet<String> = self.tracks.values().cloned().collect();
+        let mut removed: Vec<String> = Vec::new();
+        for (track, song) in previous {
+            if kept.contains(&song) || !favorites.contains(song.as_str()) {
+                continue;
+            }
+
+            match sync {
+                true if !removed.contains(&song) => removed.push(song),
+                true => {}
+                // Left for a later sync to remove
+                false => {
+                    self.tracks.entry(track).or_insert(song);
+                }
+            }
+        }
+
+        (added, removed)
+    }
+}
+
+/// What earlier imports created, so later imports can update it instead of duplicating it.
+/// Saved as JSON next to the mappings.
+#[derive(Debug, Default)]
+pub struct ImportHistory {
+    path: PathBuf,
+    entries: HistoryFile,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HistoryFile {
+    #[serde(default)]
+    playlists: Vec<ImportedPlaylist>,
+    #[serde(default)]
+    favorites: Vec<StarredSongs>,
+}
+
+impl ImportHistory {
+    /// The default location of the import history, inside the user's data directory
+    pub fn default_path() -> PathBuf {
+        dirs::data_dir()
+            .unwrap_or_default()
+            .join("TuneTracker")
+            .join("imports.json")
+    }
+
+    /// Load the history saved at a path, a missing file means nothing was imported yet
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid import history {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::load_playlists(&path)?,
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        Ok(Self { path, entries })
+    }
+
+    // Earlier versions only remembered playlists, in "playlists.json" next to the history. Those
+    // are carried over into the history, which is then saved in its own file.
+    fn load_playlists(path: &Path) -> Result<HistoryFile, String> {
+        let path = path.with_file_name("playlists.json");
+        let playlists = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid imported playlists file {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        Ok(HistoryFile {
+            playlists,
+            favorites: Vec::new(),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        }
+
+        let contents = serde_json::to_string_pretty(&self.entries).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, contents)
+            .map_err(|e| format!("Failed to write {}: {e}", self.path.display()))
+    }
+
+    /// The playlist a collection was last imported as into a destination
+    pub fn playlist(&self, provider: &str, collection: &str, destination: &str) -> Option<&str> {
+        self.entries
+            .playlists
+            .iter()
+            .find(|entry| {
+                entry.provider == provider
+                    && entry.collection == collection
+                    && entry.destination == destination
+            })
+            .map(|entry| entry.playlist_id.as_str())
+    }
+
+    /// Remember the playlist a collection was imported as, replacing any earlier one
+    pub fn insert_playlist(&mut self, playlist: ImportedPlaylist) {
+        self.entries.playlists.retain(|entry| {
+            entry.provider != playlist.provider
+                || entry.collection != playlist.collection
+                || entry.destination != playlist.destination
+        });
+        self.entries.playlists.push(playlist);
+    }
+
+    /// The songs importing a collection added to the favorites of a destination, by source track
+    pub fn starred(
+        &self,
+        provider: &str,
+        collection: &str,
+        destination: &str,
+    ) -> BTreeMap<String, String> {
+        self.entries
+            .favorites
+            .iter()
+            .find(|entry| {
+                entry.provider == provider
+                    && entry.collection == collection
+                    && entry.destination == destination
+            })
+            .map(|entry| entry.tracks.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replace the songs importing a collection added to the favorites of a destination
+    pub fn set_starred(&mut self, starred: StarredSongs) {
+        self.entries.favorites.retain(|entry| {
+            entry.provider != starred.provider
+                || entry.collection != starred.collection
+                || entry.destination != starred.destination
+        });
+        self.entries.favorites.push(starred);
+    }
+
+    /// Whether importing another collection also added a song to the favorites of a destination,
+    /// so it stays a favorite while that collection still has it
+    pub fn starred_by_other(&self, starred: &StarredSongs, song: &str) -> bool {
+        self.entries.favorites.iter().any(|entry| {
+            entry.destination == starred.destination
+                && (entry.provider != starred.provider || entry.collection != starred.collection)
+                && entry.tracks.values().any(|starred| starred == song)
+        })
+    }
+}
+
+/// The changes that turn the tracks of a playlist into the tracks of its source. Tracks are
+/// compared by ID, a song listed twice has to be listed twice in both.
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaylistDiff {
+    pub added: Vec<Track>,
+    pub removed: Vec<Track>,
+    /// Whether the tracks found in both are in a different order
+    pub reordered: bool,
+}
+
+impl PlaylistDiff {
+    pub fn new(current: &[Track], wanted: &[Track]) -> Self {
+        let (kept_wanted, added) = kept(wanted, current);
+        let (kept_current, removed) = kept(current, wanted);
+
+        Self {
+            added,
+            removed,
+            reordered: kept_current != kept_wanted,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && !self.reordered
+    }
+}
+
+/// Changes to a playlist made by removing songs by their index and adding songs at the end, the
+/// only changes the subsonic API can make without replacing every song
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaylistEdit {
+    /// Indices of the songs to remove, in the playlist before anything is removed
+    pub remove: Vec<usize>,
+    pub append: Vec<Track>,
+}
+
+impl PlaylistEdit {
+    /// The changes that turn the tracks of a playlist into the wanted tracks. Returns nothing when
+    /// songs would have to be moved or inserted between others, which these changes can't do.
+    pub fn new(current: &[Track], wanted: &[Track]) -> Option<Self> {
+        let mut remaining: HashMap<&str, usize> = HashMap::new();
+        for track in wanted {
+            *remaining.entry(&track.id).or_default() += 1;
+        }
+
+        let mut kept = Vec::new();
+        let mut remove = Vec::new();
+        for (index, track) in current.iter().enumerate() {
+            match remaining.get_mut(track.id.as_str()) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    kept.push(track.id.as_str());
+                }
+                _ => remove.push(index),
+            }
+        }
+
+        // The songs that stay have to come first in the wanted order
+        let in_order = wanted
+            .iter()
+            .zip(&kept)
+            .all(|(track, kept)| track.id == *kept);
+        if !in_order {
+            return None;
+        }
+
+        Some(Self {
+            remove,
+            append: wanted[kept.len()..].to_vec(),
+        })
+    }
+}
+
+// Splits tracks into the IDs also found in others, in order, and the tracks that aren't
+fn kept(tracks: &[Track], others: &[Track]) -> (Vec<String>, Vec<Track>) {
+    let mut remaining: HashMap<&str, usize> = HashMap::new();
+    for track in others {
+        *remaining.entry(&track.id).or_default() += 1;
+    }
+
+    let mut kept = Vec::new();
+    let mut missing = Vec::new();
+    for track in tracks {
+        match remaining.get_mut(track.id.as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                kept.push(track.id.clone());
+            }
+            _ => missing.push(track.clone()),
+        }
+    }
+
+    (kept, missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{TrackSource, test_track};
+
+    #[test]
+    fn test_playlist_diff() {
+        let song = |id: &str| Track {
+            year: 1973,
+            id: String::from(id),
+            track_source: TrackSource::Library,
+            ..test_track(id, "Led Zeppelin", "Houses of the Holy")
+        };
+        let songs = |ids: &[&str]| ids.iter().map(|id| song(id)).collect::<Vec<_>>();
+
+        let current = songs(&["1", "2", "3", "3"]);
+        assert!(PlaylistDiff::new(&current, &current).is_empty());
+
+        // A song listed twice only loses one of its entries
+        let diff = PlaylistDiff::new(&current, &songs(&["1", "3", "4"]));
+        assert_eq!(diff.added, songs(&["4"]));
+        assert_eq!(diff.removed, songs(&["2", "3"]));
+        assert!(!diff.reordered);
+
+        let diff = PlaylistDiff::new(&current, &songs(&["3", "1", "2", "3"]));
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert!(diff.reordered);
+
+        // Subsonic can remove songs and add them at the end, but not move them
+        assert_eq!(
+            PlaylistEdit::new(&current, &songs(&["1", "3", "4"])),
+            Some(PlaylistEdit {
+                remove: vec![1, 3],
+                append: songs(&["4"]),
+            })
+        );
+        assert_eq!(
+            PlaylistEdit::new(&current, &current),
+            Some(PlaylistEdit::default())
+        );
+        assert_eq!(PlaylistEdit::new(&current, &songs(&["4", "1"])), None);
+        assert_eq!(
+            PlaylistEdit::new(&current, &songs(&["3", "1", "2", "3"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_import_history() {
+        // Earlier imports are found by their source collection and destination
+        let mut history = ImportHistory::default();
+        let playlist = ImportedPlaylist {
+            provider: String::from("spotify"),
+            collection: String::from("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+            destination: String::from("jimmy@https://navidrome.example.com"),
+            playlist_id: String::from("a"),
+            name: String::from("Road Trip"),
+        };
+        history.insert_playlist(playlist.clone());
+        history.insert_playlist(ImportedPlaylist {
+            playlist_id: String::from("b"),
+            ..playlist.clone()
+        });
+        assert_eq!(
+            history.playlist(
+                "spotify",
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                "jimmy@https://navidrome.example.com"
+            ),
+            Some("b")
+        );
+        assert_eq!(
+            history.playlist(
+                "csv",
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                "jimmy@https://navidrome.example.com"
+            ),
+            None
+        );
+
+        // Playlists remembered by versions that saved them in playlists.json are carried over
+        let dir = std::env::temp_dir().join(format!("imports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("playlists.json"),
+            serde_json::to_string(&[playlist]).unwrap(),
+        )
+        .unwrap();
+        let history = ImportHistory::load(dir.join("imports.json")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            history.playlist(
+                "spotify",
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                "jimmy@https://navidrome.example.com"
+            ),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn test_favorites_sync() {
+        let starred = |tracks: &[(&str, &str)]| StarredSongs {
+            provider: String::from("spotify"),
+            collection: String::from("saved-tracks"),
+            destination: String::from("jimmy@https://navidrome.example.com"),
+            tracks: tracks
+                .iter()
+                .map(|(track, song)| (track.to_string(), song.to_string()))
+                .collect(),
+        };
+        let ids = |ids: &[&'static str]| ids.iter().copied().collect::<HashSet<&str>>();
+
+        // Songs of tracks that didn't match this time stay, only tracks removed at the source
+        // lose their song. Songs that were already favorites aren't remembered.
+        let mut liked = starred(&[("kashmir", "1"), ("ramble-on", "2"), ("dazed", "3")]);
+        let (added, removed) = liked.update(
+            &ids(&["kashmir", "ramble-on", "black-dog", "rock-and-roll"]),
+            &[("kashmir", "1"), ("black-dog", "4"), ("rock-and-roll", "5")],
+            &ids(&["1", "2", "3", "5"]),
+            true,
+        );
+        assert_eq!(added, vec![String::from("4")]);
+        assert_eq!(removed, vec![String::from("3")]);
+        assert_eq!(
+            liked.tracks,
+            starred(&[("kashmir", "1"), ("ramble-on", "2"), ("black-dog", "4")]).tracks
+        );
+
+        // A track that now matches another song swaps its song. Songs the user unstarred are
+        // added again like any other matched song
+        let mut liked = starred(&[("kashmir", "1"), ("ramble-on", "2")]);
+        let (added, removed) = liked.update(
+            &ids(&["kashmir", "ramble-on"]),
+            &[("kashmir", "6"), ("ramble-on", "2")],
+            &ids(&["1"]),
+            true,
+        );
+        assert_eq!(added, vec![String::from("6"), String::from("2")]);
+        assert_eq!(removed, vec![String::from("1")]);
+
+        // Without syncing nothing is removed, the songs of removed tracks are left for a later sync
+        let mut liked = starred(&[("kashmir", "1"), ("dazed", "3")]);
+        let (_, removed) = liked.update(
+            &ids(&["kashmir"]),
+            &[("kashmir", "1")],
+            &ids(&["1", "3"]),
+            false,
+        );
+        assert!(removed.is_empty());
+        assert_eq!(
+            liked.tracks,
+            starred(&[("kashmir", "1"), ("dazed", "3")]).tracks
+        );
+
+        // Favorites stay while another import still added them
+        let mut history = ImportHistory::default();
+        let liked = starred(&[("kashmir", "1"), ("ramble-on", "2")]);
+        history.set_starred(liked.clone());
+        history.set_starred(StarredSongs {
+            collection: String::from("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+            ..starred(&[("ramble-on", "2")])
+        });
+        assert_eq!(
+            history.starred(
+                "spotify",
+                "saved-tracks",
+                "jimmy@https://navidrome.example.com"
+            ),
+            liked.tracks
+        );
+        assert!(!history.starred_by_other(&liked, "1"));
+        assert!(history.starred_by_other(&liked, "2"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeSet;
+
+use crate::services::normalize::normalize;
+
+/// A qualifier describing which version of a song a track is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Qualifier {
+    Remaster,
+    Live,
+    Demo,
+    RoughMix,
+    RadioEdit,
+    Acoustic,
+    Instrumental,
+    Remix,
+    Extended,
+}
+
+impl Qualifier {
+    /// Cosmetic qualifiers describe the same recording, so they shouldn't affect matching
+    pub fn is_cosmetic(self) -> bool {
+        matches!(self, Qualifier::Remaster)
+    }
+
+    // Detects every qualifier in a segment of a title (eg. "2011 Remaster" or "live at the bbc")
+    fn detect(segment: &str) -> BTreeSet<Qualifier> {
+        let segment = normalize(segment);
+        let words: Vec<&str> = segment.split(' ').collect();
+        let has = |word: &str| words.contains(&word);
+        let has_phrase = |phrase: &str| format!(" {segment} ").contains(&format!(" {phrase} "));
+
+        let mut qualifiers = BTreeSet::new();
+        if has("remaster") || has("remastered") || has("remastering") {
+            qualifiers.insert(Qualifier::Remaster);
+        }
+        if has("live") || has("unplugged") {
+            qualifiers.insert(Qualifier::Live);
+        }
+        if has("demo") {
+            qualifiers.insert(Qualifier::Demo);
+        }
+        if has_phrase("rough mix") {
+            qualifiers.insert(Qualifier::RoughMix);
+        }
+        if has_phrase("radio edit") || has_phrase("radio version") || has_phrase("single edit") {
+            qualifiers.insert(Qualifier::RadioEdit);
+        }
+        if has("acoustic") || has("unplugged") {
+            qualifiers.insert(Qualifier::Acoustic);
+        }
+        if has("instrumental") {
+            qualifiers.insert(Qualifier::Instrumental);
+        }
+        if has("remix") {
+            qualifiers.insert(Qualifier::Remix);
+        }
+        if has("extended") {
+            qualifiers.insert(Qualifier::Extended);
+        }
+
+        qualifiers
+    }
+}
+
+/// A title split into the name of the song and the qualifiers describing which version it is.
+///
+/// Spotify appends qualifiers as a suffix ("St. Tristan's Sword - Rough Mix") while tagged files
+/// usually put them in brackets ("St. Tristan's Sword (rough mix)"), both are parsed the same.
+/// Suffixes and brackets that don't contain a known qualifier, like "Street Spirit (Fade Out)",
+/// are part of the song name and left in place.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ParsedTitle {
+    pub base: String,
+    pub qualifiers: BTreeSet<Qualifier>,
+}
+
+impl ParsedTitle {
+    pub fn parse(title: &str) -> Self {
+        let mut base = title.trim();
+        let mut qualifiers = BTreeSet::new();
+
+        while let Some((rest, segment)) =
+            split_dash_suffix(base).or_else(|| split_bracket_suffix(base))
+        {
+            // Leave titles that are nothing but a qualifier alone
+            let found = Qualifier::detect(segment);
+            if found.is_empty() || rest.trim().is_empty() {
+                break;
+            }
+
+            qualifiers.extend(found);
+            base = rest.trim_end();
+        }
+
+        Self {
+            base: base.to_string(),
+            qualifiers,
+        }
+    }
+
+    /// The number of non-cosmetic qualifiers only one of the titles has. A live recording
+    /// compared against a studio recording has one conflict, two remasters have none.
+    pub fn conflicts(&self, other: &Self) -> usize {
+        self.qualifiers
+            .symmetric_difference(&other.qualifiers)
+            .filter(|q| !q.is_cosmetic())
+            .count()
+    }
+}
+
+// "Title - Qualifier", only if the suffix holds a qualifier
+fn split_dash_suffix(title: &str) -> Option<(&str, &str)> {
+    title
+        .rsplit_once(" - ")
+        .filter(|(_, segment)| !Qualifier::detect(segment).is_empty())
+}
+
+// "Title (Qualifier)" or "Title [Qualifier]"
+fn split_bracket_suffix(title: &str) -> Option<(&str, &str)> {
+    let open = match title.chars().last()? {
+        ')' => '(',
+        ']' => '[',
+        _ => return None,
+    };
+
+    let start = title.rfind(open)?;
+    Some((&title[..start], &title[start + 1..title.len() - 1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_qualifiers() {
+        let spotify = ParsedTitle::parse("St. Tristan's Sword - Rough Mix");
+        let subsonic = ParsedTitle::parse("St. Tristan’s Sword (rough mix)");
+        assert_eq!(spotify.base, "St. Tristan's Sword");
+        assert_eq!(subsonic.base, "St. Tristan’s Sword");
+        assert_eq!(spotify.qualifiers, subsonic.qualifiers);
+        assert!(spotify.qualifiers.contains(&Qualifier::RoughMix));
+
+        // Brackets that aren't qualifiers are part of the title
+        let title = ParsedTitle::parse("Street Spirit (Fade Out)");
+        assert_eq!(title.base, "Street Spirit (Fade Out)");
+        assert!(title.qualifiers.is_empty());
+
+        let title = ParsedTitle::parse("Black Dog (Live) - 2007 Remaster");
+        assert_eq!(title.base, "Black Dog");
+        assert_eq!(
+            title.qualifiers.iter().copied().collect::<Vec<_>>(),
+            vec![Qualifier::Remaster, Qualifier::Live]
+        );
+
+        // Remasters are the same recording, live versions are not
+        let studio = ParsedTitle::parse("Black Dog");
+        assert_eq!(
+            studio.conflicts(&ParsedTitle::parse("Black Dog - Remaster")),
+            0
+        );
+        assert_eq!(studio.conflicts(&title), 1);
+    }
+}